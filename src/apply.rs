@@ -0,0 +1,944 @@
+//! The hunk matching behind the patch binary: unified diff hunks applied
+//! in a single pass to a stream of lines, without touching the filesystem.
+
+use crate::engine::{diff, Diff, DiffOptions};
+use anyhow::Result;
+use log::debug;
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Seek, SeekFrom, Write};
+use std::iter;
+use std::ops::Range;
+
+/// How hunks are matched against the input.
+#[derive(Debug, Clone, Default)]
+pub struct PatchOptions {
+    /// Apply hunks backwards, removing what they add (-R).
+    pub reverse: bool,
+
+    /// Ignore whitespace when matching lines (-l).
+    pub loose: bool,
+
+    /// How many lines of leading and of trailing context may mismatch.
+    /// Defaults to one less than the hunk's leading context (-F).
+    pub fuzz: Option<usize>,
+
+    /// Only match after an input line starting with this, leading
+    /// whitespace aside: the function diff -p named in the hunk header.
+    pub anchor: Option<Vec<u8>>,
+}
+
+/// How many hunks of a patch applied, and what was in the way of the ones
+/// that didn't.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub applied: usize,
+
+    pub failed: usize,
+
+    /// One for each hunk that failed, whether it wasn't found or was
+    /// corrupt.
+    pub conflicts: Vec<Conflict>,
+}
+
+/// A hunk that didn't apply, and what the input had where it was expected.
+/// A corrupt hunk expects only the lines it got as far as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    /// Which hunk of the patch it is, from 1.
+    pub hunk: usize,
+
+    /// The input line it was expected at, from 1: where its header says,
+    /// moved as far as the last hunk that applied had moved.
+    pub line: usize,
+
+    /// The lines it expected there: its context and the lines it removes.
+    pub expected: Vec<Vec<u8>>,
+
+    /// As many lines as the input actually has there, up to its end.
+    pub actual: Vec<Vec<u8>>,
+
+    /// Where the actual lines start and end in the input, in bytes.
+    pub bytes: Range<u64>,
+}
+
+/// What became of a hunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The hunk was found, its old lines starting at input line `at`.
+    Applied { at: usize },
+
+    /// The hunk wasn't found. If the lines it would add turned up instead,
+    /// `reversed_at` says on what input line: the patch may be reversed.
+    Failed { reversed_at: Option<usize> },
+}
+
+/// Iterate over the lines of `r` as bytes, whether or not they're UTF-8,
+/// without the '\n' (or "\r\n") at the end.
+pub fn byte_lines<R: BufRead>(mut r: R) -> impl Iterator<Item = Vec<u8>> {
+    iter::from_fn(move || {
+        let mut line = Vec::new();
+        match r.read_until(b'\n', &mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => {
+                if line.ends_with(b"\n") {
+                    line.pop();
+                    if line.ends_with(b"\r") {
+                        line.pop();
+                    }
+                }
+                Some(line)
+            }
+        }
+    })
+}
+
+/// Compare ignoring whitespace, ordering like the strings with all the
+/// whitespace taken out.
+pub fn loosecmp(aa: &[u8], bb: &[u8]) -> Ordering {
+    let aa = aa.iter().filter(|c| !c.is_ascii_whitespace());
+    let bb = bb.iter().filter(|c| !c.is_ascii_whitespace());
+
+    aa.cmp(bb)
+}
+
+/// How far a Patcher had got, to go back to.
+#[derive(Debug, Clone, Copy)]
+pub struct Mark {
+    input: u64,
+    output: u64,
+    linenum: usize,
+    written: u64,
+}
+
+/// A file being patched: input is copied to output as hunks are applied,
+/// which must come in the order they appear in the file.
+#[derive(Debug)]
+pub struct Patcher<R, W> {
+    input: R,
+    output: W,
+
+    /// Input lines read so far.
+    linenum: usize,
+
+    /// The input ends its lines in CRLF, so added lines should too.
+    crlf: bool,
+
+    /// Bytes written to the output so far.
+    written: u64,
+}
+
+impl<R: BufRead, W: Write> Patcher<R, W> {
+    pub fn new(input: R, output: W) -> Self {
+        Patcher {
+            input,
+            output,
+            linenum: 0,
+            crlf: false,
+            written: 0,
+        }
+    }
+
+    /// Read a line of input. Unlike lines(), this leaves the '\r' of a
+    /// CRLF file alone, so it's written back out the way it came in.
+    fn read_line(&mut self) -> Result<Option<Vec<u8>>> {
+        let mut line = Vec::new();
+        if self.input.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        if line.ends_with(b"\n") {
+            line.pop();
+        }
+
+        self.linenum += 1;
+        if self.linenum == 1 {
+            self.crlf = line.ends_with(b"\r");
+        }
+        debug!("IN: {:?}", String::from_utf8_lossy(&line));
+
+        Ok(Some(line))
+    }
+
+    fn write_line(&mut self, line: &[u8]) -> Result<()> {
+        self.write(line)?;
+        self.write(b"\n")
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.output.write_all(data)?;
+        self.written += data.len() as u64;
+
+        Ok(())
+    }
+
+    /// Input lines that were read for a hunk that didn't apply go out as
+    /// they are.
+    fn fail(&mut self, buf: VecDeque<Vec<u8>>, backwarn: Option<usize>) -> Result<Outcome> {
+        for line in buf {
+            self.write_line(&line)?;
+        }
+
+        Ok(Outcome::Failed { reversed_at: backwarn })
+    }
+
+    /// Given a hunk of a unified diff, its lines starting with ' ', '+' or
+    /// '-', make the appropriate change. This does not use the location
+    /// information, but instead treats a hunk as a sort of regex. Copies
+    /// data from input to output until it finds the change to be made,
+    /// then outputs the changed data and returns. (Finding EOF first is a
+    /// failure.) A "\ No newline at end of file" line after the last line
+    /// of the new side leaves the output without one.
+    pub fn apply_hunk(&mut self, hunk: &[Vec<u8>], opts: &PatchOptions) -> Result<Outcome> {
+        let mut trail = 0;
+        let mut backwarn = None;
+        let mut fuzz = 0;
+
+        // The hunk lines we'd be adding, and the ones we'd be removing.
+        let (add, del): (&[u8], &[u8]) = match opts.reverse {
+            true => (b"-", b"+"),
+            false => (b"+", b"-"),
+        };
+
+        let noeol = hunk.windows(2).any(|v| v[1].starts_with(b"\\") && !v[0].starts_with(del));
+        let hunk: Vec<&[u8]> = hunk.iter()
+            .filter(|v| !v.starts_with(b"\\"))
+            .map(|v| v.as_slice())
+            .collect();
+
+        let lcmp = |aa: &[u8], bb: &[u8]| {
+            let aa = aa.strip_suffix(b"\r").unwrap_or(aa);
+
+            match opts.loose {
+                true => loosecmp(aa, bb),
+                false => aa.cmp(bb)
+            }
+        };
+
+        // Match EOF if there aren't as many ending context lines as beginning
+        for line in &hunk {
+            match line.starts_with(b" ") {
+                true => trail += 1,
+                false => trail = 0,
+            }
+
+            // Only allow fuzz if 2 context lines have multiple nonwhitespace chars.
+            // avoids the "all context was blank or } lines" issue. Removed lines
+            // count as context since they're matched.
+            if line.starts_with(b" ") || line.starts_with(del) {
+                let mut s = line[1..].iter().skip_while(|c| c.is_ascii_whitespace());
+
+                if let Some(v) = s.nth(1) {
+                    if !v.is_ascii_whitespace() {
+                        fuzz += 1;
+                    }
+                }
+            }
+
+            debug!("HUNK:{}", String::from_utf8_lossy(line));
+        }
+
+        let hunklen = hunk.len();
+        let lead = hunk.iter().take_while(|c| c.starts_with(b" ")).count();
+        let matcheof = trail == 0 || trail < lead;
+
+        // A hunk that has to match the start of the file can't come after
+        // its function line.
+        let mut anchored = opts.anchor.is_none() || lead == 0 || trail > lead;
+        let is_anchor = |line: &[u8]| match &opts.anchor {
+            Some(v) => line[line.iter().take_while(|c| c.is_ascii_whitespace()).count()..].starts_with(v),
+            None => false,
+        };
+        let allfuzz = match fuzz.cmp(&2) {
+            Ordering::Less => 0,
+            _ => opts.fuzz.unwrap_or_else(|| lead.saturating_sub(1)),
+        };
+
+        debug!("MATCHEOF={}", matcheof);
+
+        // Loop through input data searching for this hunk. Match all context
+        // lines and lines to be removed until we've found end of complete hunk.
+        // plist indexes the next hunk line to match, and buf holds the input
+        // lines matched so far. Fuzz lets up to allfuzz lines of leading and
+        // of trailing context mismatch.
+        let fuzzable = |plist: usize| {
+            plist < lead.min(allfuzz) || plist >= hunklen - trail.min(allfuzz)
+        };
+        let mut plist = 0;
+        let mut buf: VecDeque<Vec<u8>> = VecDeque::new();
+
+        'search: loop {
+            let data = self.read_line()?;
+
+            // Figure out which line of hunk to compare with next. (Skip lines
+            // of the hunk we'd be adding.)
+            while plist < hunklen && hunk[plist].starts_with(add) {
+                if let Some(d) = &data {
+                    if lcmp(d, &hunk[plist][1..]) == Ordering::Equal && backwarn.is_none() {
+                        backwarn = Some(self.linenum);
+                    }
+                }
+                plist += 1;
+            }
+
+            // Is this EOF?
+            match data {
+                Some(v) => buf.push_back(v),
+                None => {
+                    debug!("INEOF");
+
+                    // Does this hunk need to match EOF?
+                    if plist == hunklen && matcheof && anchored {
+                        break;
+                    }
+
+                    // File ended before we found a place for this hunk.
+                    return self.fail(buf, backwarn);
+                }
+            }
+
+            // Compare this line with next expected line of hunk. Match can fail
+            // because next line doesn't match, or because we hit end of a hunk that
+            // needed EOF and this isn't EOF.
+            let mut check = buf.len() - 1;
+
+            loop {
+                if plist == hunklen || lcmp(&buf[check], &hunk[plist][1..]) != Ordering::Equal {
+                    // Match failed: can we fuzz it?
+                    if plist < hunklen && hunk[plist].starts_with(b" ") && fuzzable(plist) {
+                        debug!("FUZZED: {} {}", self.linenum, String::from_utf8_lossy(hunk[plist]));
+                    } else {
+                        debug!("NOT: {}", hunk.get(plist).map(|v| String::from_utf8_lossy(v)).unwrap_or_default());
+
+                        // A line we'd remove that's already been replaced by
+                        // what we'd add means the patch may be reversed.
+                        if plist < hunklen && hunk[plist].starts_with(del) && backwarn.is_none() {
+                            let change = |c: &&&[u8]| !c.starts_with(b" ");
+                            let start = plist - hunk[..plist].iter().rev().take_while(change).count();
+                            let added = hunk[start..].iter()
+                                .take_while(change)
+                                .any(|c| c.starts_with(add) && lcmp(&buf[check], &c[1..]) == Ordering::Equal);
+                            if added {
+                                backwarn = Some(self.linenum);
+                            }
+                        }
+
+                        // If this hunk must match start of file, fail if it didn't.
+                        if lead == 0 || trail > lead {
+                            return self.fail(buf, backwarn);
+                        }
+
+                        // Write out first line of buffer and recheck rest for new match.
+                        if let Some(v) = buf.pop_front() {
+                            anchored |= is_anchor(&v);
+                            self.write_line(&v)?;
+                        }
+                        plist = 0;
+
+                        // If end of the buffer without finishing a match, read more lines.
+                        if buf.is_empty() {
+                            break;
+                        }
+
+                        check = 0;
+                        continue;
+                    }
+                } else {
+                    debug!("MAYBE: {:?}", String::from_utf8_lossy(hunk[plist]));
+                }
+
+                // This line matches (or was fuzzed). Advance plist past it and
+                // any lines we'd be adding, and detect successful match.
+                plist += 1;
+                while plist < hunklen && hunk[plist].starts_with(add) {
+                    plist += 1;
+                }
+                // (A match before the function line isn't one: keep going,
+                // and the next line fails to match past the end of the hunk.)
+                if plist == hunklen && !matcheof && anchored {
+                    break 'search;
+                }
+                check += 1;
+                if check == buf.len() {
+                    break;
+                }
+            }
+        }
+
+        // We have a match.  Emit changed data. Context comes from the input
+        // rather than the hunk, so lines that were fuzzed stay as they were.
+        let at = self.linenum + 1 - buf.len();
+        let last = hunk.iter().rposition(|v| !v.starts_with(del));
+        for (i, line) in hunk.iter().enumerate() {
+            // The last line, if it has no newline, has no '\r' either.
+            let line = if line.starts_with(add) {
+                match self.crlf && !(noeol && Some(i) == last) {
+                    true => [&line[1..], b"\r"].concat(),
+                    false => line[1..].to_vec(),
+                }
+            } else {
+                match buf.pop_front() {
+                    Some(v) if !line.starts_with(del) => v,
+                    _ => continue,
+                }
+            };
+            match noeol && Some(i) == last {
+                true => self.write(&line)?,
+                false => self.write_line(&line)?,
+            }
+        }
+        for line in buf {
+            self.write_line(&line)?;
+        }
+
+        Ok(Outcome::Applied { at })
+    }
+
+    /// Copy up to n lines of input straight to the output.
+    fn copy_lines(&mut self, n: usize) -> Result<()> {
+        for _ in 0..n {
+            match self.read_line()? {
+                Some(v) => self.write_line(&v)?,
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Put a hunk that didn't apply in the output between conflict markers,
+    /// as close to input line `line` as we can still get: the lines the
+    /// hunk would replace as they are in the input, then as the hunk would
+    /// have them.
+    pub fn conflict(&mut self, hunk: &[Vec<u8>], line: usize, opts: &PatchOptions) -> Result<()> {
+        let (add, del): (&[u8], &[u8]) = match opts.reverse {
+            true => (b"-", b"+"),
+            false => (b"+", b"-"),
+        };
+        let hunk = hunk.iter().filter(|v| !v.starts_with(b"\\"));
+        let eol: &[u8] = match self.crlf {
+            true => b"\r",
+            false => b"",
+        };
+
+        self.copy_lines(line.saturating_sub(self.linenum + 1))?;
+
+        self.write_line(&[b"<<<<<<<", eol].concat())?;
+        self.copy_lines(hunk.clone().filter(|l| !l.starts_with(add)).count())?;
+        self.write_line(&[b"=======", eol].concat())?;
+        for l in hunk.filter(|l| !l.starts_with(del)) {
+            self.write_line(&[&l[1..], eol].concat())?;
+        }
+        self.write_line(&[b">>>>>>>", eol].concat())?;
+
+        Ok(())
+    }
+
+    /// Three-way merge a hunk that didn't apply with the input at line
+    /// `line`: its old lines are the base, the input lines there are ours
+    /// and its new lines are theirs. Changes only one side made go in, and
+    /// where both changed the same lines differently, both go between
+    /// conflict markers. Returns whether it merged without conflicts.
+    pub fn merge(&mut self, hunk: &[Vec<u8>], line: usize, opts: &PatchOptions) -> Result<bool> {
+        let (add, del): (&[u8], &[u8]) = match opts.reverse {
+            true => (b"-", b"+"),
+            false => (b"+", b"-"),
+        };
+        let hunk: Vec<&[u8]> = hunk.iter().filter(|v| !v.starts_with(b"\\")).map(|v| v.as_slice()).collect();
+        let base: Vec<&[u8]> = hunk.iter().filter(|l| !l.starts_with(add)).map(|l| &l[1..]).collect();
+        let theirs: Vec<&[u8]> = hunk.iter().filter(|l| !l.starts_with(del)).map(|l| &l[1..]).collect();
+        let eol: &[u8] = match self.crlf {
+            true => b"\r",
+            false => b"",
+        };
+
+        self.copy_lines(line.saturating_sub(self.linenum + 1))?;
+
+        // Our side of it is whatever the lines the hunk would have replaced
+        // have turned into. Read twice as many, in case lines were added,
+        // and leave out any that come after the last of them.
+        let mut window = vec![];
+        while window.len() < 2 * base.len() {
+            match self.read_line()? {
+                Some(v) => window.push(v),
+                None => break,
+            }
+        }
+        let window: Vec<&[u8]> = window.iter().map(|v| v.strip_suffix(b"\r").unwrap_or(v)).collect();
+
+        let opts = DiffOptions::default();
+        let mut changes = [diff(&base, &window, &opts), diff(&base, &theirs, &opts)];
+        let (ours, tail) = match changes[0].last() {
+            Some(d) if d.a == base.len() + 1 => window.split_at(d.c - 1),
+            _ => (&window[..], &[][..]),
+        };
+        if !tail.is_empty() {
+            changes[0].pop();
+        }
+        let sides = [ours, &theirs[..]];
+        let mut next = [0, 0];
+        let mut shift = [0isize; 2];
+        let mut pos = 1;
+        let mut clean = true;
+
+        // Where base line n is in ours, 0-based.
+        let at = |n: usize, shift: isize| (n as isize - 1 + shift) as usize;
+
+        loop {
+            // Changes either side made to the same base lines, or lines
+            // both inserted in the same place, are taken together.
+            let first = |next: &[usize; 2]| (0..2)
+                .filter(|&k| next[k] < changes[k].len())
+                .min_by_key(|&k| changes[k][next[k]].a);
+            let k = match first(&next) {
+                Some(k) => k,
+                None => break,
+            };
+            let (lo, mut hi) = (changes[k][next[k]].a, changes[k][next[k]].b);
+            let mut took = [0, 0];
+            let overlaps = |d: &Diff, hi: usize| d.a <= hi || (lo > hi && d.a == lo && d.b < d.a);
+            while let Some(k) = first(&next).filter(|&k| overlaps(&changes[k][next[k]], hi)) {
+                let d = &changes[k][next[k]];
+                hi = hi.max(d.b);
+                took[k] += 1;
+                next[k] += 1;
+            }
+
+            // Base lines up to here are the same on all sides.
+            for l in &ours[at(pos, shift[0])..at(lo, shift[0])] {
+                self.write_line(&[l, eol].concat())?;
+            }
+
+            // What each side has for base lines lo..=hi.
+            let mut text = [&[][..], &[][..]];
+            for k in 0..2 {
+                let start = at(lo, shift[k]);
+                for d in &changes[k][next[k] - took[k]..next[k]] {
+                    shift[k] += (d.d + 1 - d.c) as isize - (d.b + 1 - d.a) as isize;
+                }
+                text[k] = &sides[k][start..at(hi + 1, shift[k])];
+            }
+
+            if took[1] == 0 || text[0] == text[1] {
+                for l in text[0] {
+                    self.write_line(&[l, eol].concat())?;
+                }
+            } else if took[0] == 0 {
+                for l in text[1] {
+                    self.write_line(&[l, eol].concat())?;
+                }
+            } else {
+                clean = false;
+                self.write_line(&[b"<<<<<<<", eol].concat())?;
+                for l in text[0] {
+                    self.write_line(&[l, eol].concat())?;
+                }
+                self.write_line(&[b"=======", eol].concat())?;
+                for l in text[1] {
+                    self.write_line(&[l, eol].concat())?;
+                }
+                self.write_line(&[b">>>>>>>", eol].concat())?;
+            }
+            pos = hi + 1;
+        }
+        for l in ours[at(pos, shift[0]).min(ours.len())..].iter().chain(tail) {
+            self.write_line(&[l, eol].concat())?;
+        }
+
+        Ok(clean)
+    }
+
+    /// Copy the rest of the input to the output.
+    pub fn copy_rest(&mut self) -> Result<()> {
+        self.written += io::copy(&mut self.input, &mut self.output)?;
+
+        Ok(())
+    }
+
+    /// Copy the rest of the input to the output, and hand both back.
+    pub fn finish(mut self) -> Result<(R, W)> {
+        self.copy_rest()?;
+
+        Ok(self.into_inner())
+    }
+
+    /// How many bytes have been written to the output.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Hand back the input and output as they are, to start over or give up.
+    pub fn into_inner(self) -> (R, W) {
+        (self.input, self.output)
+    }
+}
+
+impl<R: BufRead + Seek, W: Write + Seek> Patcher<R, W> {
+    /// Remember how far input and output have got.
+    pub fn mark(&mut self) -> Result<Mark> {
+        Ok(Mark {
+            input: self.input.stream_position()?,
+            output: self.output.stream_position()?,
+            linenum: self.linenum,
+            written: self.written,
+        })
+    }
+
+    /// Go back to a mark, so what's been read since is read again and
+    /// what's been written since is written over.
+    pub fn rewind(&mut self, mark: &Mark) -> Result<()> {
+        self.input.seek(SeekFrom::Start(mark.input))?;
+        self.output.seek(SeekFrom::Start(mark.output))?;
+        self.linenum = mark.linenum;
+        self.written = mark.written;
+
+        Ok(())
+    }
+}
+
+/// Gathers the lines of a hunk of a unified diff, as many of each side as
+/// its header says, for the patch binary and apply_unified alike.
+#[derive(Debug, Default)]
+pub struct HunkReader {
+    /// The hunk's lines so far, starting with ' ', '+', '-' or '\\'.
+    pub lines: Vec<Vec<u8>>,
+
+    /// Old and new lines still to come.
+    oldlen: usize,
+    newlen: usize,
+}
+
+/// What became of a line offered to a HunkReader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HunkLine {
+    /// It's part of the hunk.
+    Taken,
+
+    /// It's a line of the hunk the header didn't leave room for.
+    TooLong,
+
+    /// It isn't part of the hunk. If the hunk isn't done, it was cut short.
+    Other,
+}
+
+impl HunkReader {
+    /// Start a hunk whose header says it has `oldlen` old lines and
+    /// `newlen` new ones.
+    pub fn new(oldlen: usize, newlen: usize) -> Self {
+        HunkReader {
+            lines: vec![],
+            oldlen,
+            newlen,
+        }
+    }
+
+    /// Whether all the lines the header promised have come.
+    pub fn is_done(&self) -> bool {
+        self.oldlen + self.newlen == 0
+    }
+
+    /// Offer the next line of the patch. A "\\ No newline at end of file"
+    /// goes with the line before it, even once the hunk's done. An empty
+    /// line is a context line that lost its space to a mailer or an editor.
+    pub fn push(&mut self, line: &[u8]) -> HunkLine {
+        if line.starts_with(b"\\") && !self.lines.is_empty() {
+            self.lines.push(line.to_vec());
+            return HunkLine::Taken;
+        }
+        if self.is_done() {
+            return HunkLine::Other;
+        }
+
+        let line = match line.is_empty() && self.oldlen > 0 && self.newlen > 0 {
+            true => &b" "[..],
+            false => line,
+        };
+        let fits = match line.first() {
+            Some(b' ') => self.oldlen > 0 && self.newlen > 0,
+            Some(b'-') => self.oldlen > 0,
+            Some(b'+') => self.newlen > 0,
+            _ => return HunkLine::Other,
+        };
+        if !fits {
+            return HunkLine::TooLong;
+        }
+
+        if line[0] != b'+' {
+            self.oldlen -= 1;
+        }
+        if line[0] != b'-' {
+            self.newlen -= 1;
+        }
+        self.lines.push(line.to_vec());
+
+        HunkLine::Taken
+    }
+}
+
+/// Parse the old and new starting lines and lengths out of a hunk header,
+/// `@@ -oldline[,oldlen] +newline[,newlen] @@`, where a missing len is 1.
+pub fn hunk_range(line: &[u8]) -> Option<[(usize, usize); 2]> {
+    let line = std::str::from_utf8(line.strip_prefix(b"@@ -")?).ok()?;
+    let mut s = line.split(' ');
+    let range = |v: &str| match v.split_once(',') {
+        Some((start, len)) => Some((start.parse().ok()?, len.parse().ok()?)),
+        None => Some((v.parse().ok()?, 1)),
+    };
+
+    Some([range(s.next()?)?, range(s.next()?.strip_prefix('+')?)?])
+}
+
+/// What `data` has at `line`, for a hunk that expected `expected` there.
+fn conflict(data: &[u8], hunk: usize, line: usize, expected: Vec<Vec<u8>>) -> Conflict {
+    let mut start = 0;
+    let mut lines = data.split_inclusive(|&c| c == b'\n');
+    for v in lines.by_ref().take(line.saturating_sub(1)) {
+        start += v.len();
+    }
+
+    let mut end = start;
+    let actual = lines.take(expected.len())
+        .map(|v| {
+            end += v.len();
+            let v = v.strip_suffix(b"\n").unwrap_or(v);
+            v.strip_suffix(b"\r").unwrap_or(v).to_vec()
+        })
+        .collect();
+
+    Conflict {
+        hunk,
+        line,
+        expected,
+        actual,
+        bytes: start as u64..end as u64,
+    }
+}
+
+/// Apply the hunks of a unified diff of one file from `patch` to `input`,
+/// writing the result to `out`. File headers and anything else between
+/// hunks are skipped. A hunk that fails leaves its part of the input as it
+/// was, and the rest of the hunks are still tried. The input is read into
+/// memory first, to say what was in the way of the hunks that failed.
+pub fn apply_unified(
+    patch: impl BufRead,
+    mut input: impl BufRead,
+    out: impl Write,
+    opts: &PatchOptions,
+) -> Result<Stats> {
+    let mut data = vec![];
+    input.read_to_end(&mut data)?;
+
+    let mut patcher = Patcher::new(&data[..], out);
+    let mut stats = Stats::default();
+    let mut hunk: Option<HunkReader> = None;
+    let mut hunknum = 0;
+    let mut range = [(0, 0); 2];
+
+    // How far the last hunk that applied was from where it said it'd be.
+    let mut offset = 0;
+
+    let mut finish = |hunk: HunkReader, hunknum: usize, range: [(usize, usize); 2], stats: &mut Stats| -> Result<()> {
+        // A hunk with no old lines goes after the line it names.
+        let (line, add) = match opts.reverse {
+            true => (range[1].0 + (range[1].1 == 0) as usize, b'-'),
+            false => (range[0].0 + (range[0].1 == 0) as usize, b'+'),
+        };
+
+        // A corrupt hunk isn't tried, but says what it was after.
+        let outcome = match hunk.is_done() {
+            true => patcher.apply_hunk(&hunk.lines, opts)?,
+            false => Outcome::Failed { reversed_at: None },
+        };
+        match outcome {
+            Outcome::Applied { at } => {
+                offset = at as isize - line as isize;
+                stats.applied += 1;
+            },
+            Outcome::Failed { .. } => {
+                let expected = hunk.lines.iter()
+                    .filter(|v| v.first() != Some(&add) && !v.starts_with(b"\\"))
+                    .map(|v| v[1..].to_vec())
+                    .collect();
+                let line = (line as isize + offset).max(1) as usize;
+                stats.conflicts.push(conflict(&data, hunknum, line, expected));
+                stats.failed += 1;
+            },
+        }
+        Ok(())
+    };
+
+    for line in byte_lines(patch) {
+        if let Some(v) = hunk.as_mut() {
+            // A hunk too long for its header is corrupt, and the rest of
+            // it is skipped with everything else that isn't a header.
+            if v.push(&line) == HunkLine::Taken {
+                continue;
+            }
+            finish(hunk.take().unwrap_or_default(), hunknum, range, &mut stats)?;
+        }
+
+        if let Some(v) = hunk_range(&line) {
+            range = v;
+            hunk = Some(HunkReader::new(v[0].1, v[1].1));
+            hunknum += 1;
+        }
+    }
+    if let Some(v) = hunk {
+        finish(v, hunknum, range, &mut stats)?;
+    }
+
+    patcher.finish()?;
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(patch: &str, input: &[u8]) -> (Stats, Vec<u8>) {
+        let mut out = vec![];
+        let stats = apply_unified(patch.as_bytes(), input, &mut out, &PatchOptions::default()).unwrap();
+
+        (stats, out)
+    }
+
+    #[test]
+    fn loose() {
+        use Ordering::*;
+
+        let cases: &[(&str, &str, Ordering)] = &[
+            ("  leading", "leading", Equal),
+            ("trailing \t", "trailing", Equal),
+            ("in ter\tnal", "internal", Equal),
+            (" a b ", "ab", Equal),
+            ("", "  \t", Equal),
+            ("a b", "a c", Less),
+            ("b", " a c", Greater),
+            ("ab", "a b c", Less),
+        ];
+
+        for &(a, b, want) in cases {
+            assert_eq!(loosecmp(a.as_bytes(), b.as_bytes()), want, "{:?} {:?}", a, b);
+            assert_eq!(loosecmp(b.as_bytes(), a.as_bytes()), want.reverse(), "{:?} {:?}", b, a);
+        }
+    }
+
+    fn hunk(lines: &[&str]) -> Vec<Vec<u8>> {
+        lines.iter().map(|v| v.as_bytes().to_vec()).collect()
+    }
+
+    #[test]
+    fn patcher_output() {
+        // Input lines go out whole, and the ones a hunk adds without the
+        // '+' in front.
+        let mut out = vec![];
+        let mut p = Patcher::new(&b"head\nkeep\nold\nkeep2\ntail\n"[..], &mut out);
+        let outcome = p.apply_hunk(&hunk(&[" keep", "-old", "+new", " keep2"]), &PatchOptions::default()).unwrap();
+        assert_eq!(outcome, Outcome::Applied { at: 2 });
+        p.finish().unwrap();
+        assert_eq!(out, b"head\nkeep\nnew\nkeep2\ntail\n");
+
+        // A hunk that isn't found leaves what it read as it was.
+        let mut out = vec![];
+        let mut p = Patcher::new(&b"head\nkeep\nold\n"[..], &mut out);
+        let outcome = p.apply_hunk(&hunk(&[" keep", "-gone", "+new", " keep2"]), &PatchOptions::default()).unwrap();
+        assert_eq!(outcome, Outcome::Failed { reversed_at: None });
+        p.finish().unwrap();
+        assert_eq!(out, b"head\nkeep\nold\n");
+    }
+
+    const LINES: &[u8] = b"one\ntwo\nthree\nfour\nfive\nsix\nseven\n";
+
+    #[test]
+    fn applied() {
+        let patch = "--- a\n+++ b\n@@ -2,3 +2,3 @@\n two\n-three\n+THREE\n four\n";
+        let (stats, out) = apply(patch, LINES);
+
+        assert_eq!((stats.applied, stats.failed), (1, 0));
+        assert!(stats.conflicts.is_empty());
+        assert_eq!(out, b"one\ntwo\nTHREE\nfour\nfive\nsix\nseven\n");
+    }
+
+    #[test]
+    fn offset() {
+        let patch = "@@ -2,3 +2,3 @@\n two\n-three\n+THREE\n four\n@@ -5,3 +5,3 @@\n five\n-six\n+SIX\n seven\n";
+        let (stats, out) = apply(patch, b"zero\none\ntwo\nthree\nfour\nfive\nsix-ish\nseven\n");
+
+        assert_eq!((stats.applied, stats.failed), (1, 1));
+        assert_eq!(out, b"zero\none\ntwo\nTHREE\nfour\nfive\nsix-ish\nseven\n");
+
+        // The second hunk is looked for one line on, as the first was.
+        let c = &stats.conflicts[0];
+        assert_eq!((c.hunk, c.line), (2, 6));
+        assert_eq!(c.expected, [&b"five"[..], b"six", b"seven"]);
+        assert_eq!(c.actual, [&b"five"[..], b"six-ish", b"seven"]);
+        assert_eq!(c.bytes, 24..43);
+    }
+
+    #[test]
+    fn failed() {
+        let patch = "@@ -2,3 +2,3 @@\n two\n-tres\n+THREE\n four\n";
+        let (stats, out) = apply(patch, LINES);
+
+        assert_eq!((stats.applied, stats.failed), (0, 1));
+        assert_eq!(stats.conflicts[0].expected, [&b"two"[..], b"tres", b"four"]);
+        assert_eq!(stats.conflicts[0].actual, [&b"two"[..], b"three", b"four"]);
+        assert_eq!(out, LINES);
+    }
+
+    #[test]
+    fn corrupt() {
+        // Cut short, and longer than its header says.
+        let patch = "@@ -2,3 +2,3 @@\n two\n-three\nnot a hunk line\n@@ -5,1 +5,1 @@\n-five\n+FIVE\n six\n";
+        let (stats, out) = apply(patch, LINES);
+
+        assert_eq!((stats.applied, stats.failed), (0, 2));
+        assert_eq!(stats.conflicts.iter().map(|c| c.hunk).collect::<Vec<_>>(), [1, 2]);
+        assert_eq!(stats.conflicts[0].expected, [&b"two"[..], b"three"]);
+        assert_eq!(out, LINES);
+    }
+
+    #[test]
+    fn empty_context_line() {
+        let patch = "@@ -1,4 +1,4 @@\n a\n\n-b\n+B\n c\n";
+        let (stats, out) = apply(patch, b"a\n\nb\nc\n");
+
+        assert_eq!(stats.applied, 1);
+        assert_eq!(out, b"a\n\nB\nc\n");
+    }
+
+    const PATCH: &str = "@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n";
+
+    #[test]
+    fn crlf_patch_on_crlf_file() {
+        let (stats, out) = apply(&PATCH.replace('\n', "\r\n"), b"a\r\nb\r\nc\r\n");
+
+        assert_eq!(stats.applied, 1);
+        assert_eq!(out, b"a\r\nB\r\nc\r\n");
+    }
+
+    #[test]
+    fn lf_patch_on_crlf_file() {
+        let (stats, out) = apply(PATCH, b"a\r\nb\r\nc\r\n");
+
+        assert_eq!(stats.applied, 1);
+        assert_eq!(out, b"a\r\nB\r\nc\r\n");
+    }
+
+    #[test]
+    fn crlf_patch_on_lf_file() {
+        let (stats, out) = apply(&PATCH.replace('\n', "\r\n"), b"a\nb\nc\n");
+
+        assert_eq!(stats.applied, 1);
+        assert_eq!(out, b"a\nB\nc\n");
+    }
+
+    #[test]
+    fn crlf_without_newline_at_end() {
+        let patch = "@@ -1,2 +1,3 @@\n a\n b\n+tail\n\\ No newline at end of file\n";
+        let (stats, out) = apply(patch, b"a\r\nb\r\n");
+
+        assert_eq!(stats.applied, 1);
+        assert_eq!(out, b"a\r\nb\r\ntail");
+    }
+}
@@ -1,10 +1,13 @@
-use anyhow::Result;
-use std::cmp::Ordering;
+use anyhow::{Context, Result};
 use std::fs;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::path::{Path, PathBuf};
+use std::collections::VecDeque;
 use std::io;
-use std::io::Read;
+use std::io::{BufRead, BufReader, Read, Stdin};
+use std::iter::{self, FromIterator};
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn devnull() -> &'static Path {
     #[cfg(not(windows))]
@@ -14,69 +17,138 @@ pub fn devnull() -> &'static Path {
     return Path::new("nul");
 }
 
-/// Open a temporary file to copy an existing file into.
-pub fn copy_tempfile(name: &Path) -> Result<(PathBuf, File)> {
-    let tempname: PathBuf = [name, Path::new("XXXXXX")].iter().collect();
-    let file = File::create(&tempname)?;
-    let statbuf = fs::metadata(name)?.permissions();
-    fs::set_permissions(&tempname, statbuf)?;
-    Ok((tempname, file))
-}
+/// Open a temporary file next to `dest` to copy the file `name` into, with
+/// the same permissions if it exists. Like mkstemp(), the name ends in six
+/// random characters, and we keep trying until we get one nobody else has.
+pub fn copy_tempfile(name: &Path, dest: &Path) -> Result<(PathBuf, File)> {
+    const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
 
-/// Compare ignoring whitespace. Just returns 0/1, no > or <
-pub fn loosecmp(aa: &str, bb: &str) -> Ordering {
-    let mut aa = aa.chars().peekable();
-    let mut bb = bb.chars().peekable();
+    // xorshift seeded from the clock and pid is plenty for picking names.
+    let mut seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_nanos() as u64)
+        .unwrap_or_default()
+        ^ ((process::id() as u64) << 32)
+        | 1;
 
     loop {
-        while aa.next_if(|c| c.is_ascii_whitespace()).is_some() {}
-        while bb.next_if(|c| c.is_ascii_whitespace()).is_some() {}
-        if aa.peek() != bb.peek() {
-            return Ordering::Greater;
+        let mut tempname = dest.as_os_str().to_owned();
+        for _ in 0..6 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            tempname.push((CHARS[(seed % CHARS.len() as u64) as usize] as char).to_string());
+        }
+        let tempname = PathBuf::from(tempname);
+
+        let file = match OpenOptions::new().write(true).create_new(true).open(&tempname) {
+            Ok(v) => v,
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if let Ok(v) = fs::metadata(name) {
+            if let Err(e) = fs::set_permissions(&tempname, v.permissions()) {
+                let _ = fs::remove_file(&tempname);
+                return Err(e.into());
+            }
+        }
+        return Ok((tempname, file));
+    }
+}
+
+/// Move `from` to `to`. If they're on different filesystems, copy it over
+/// (permissions and all) and remove the original instead.
+pub fn rename(from: &Path, to: &Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            fs::copy(from, to).with_context(|| {
+                format!("can't copy {} to {}", from.to_string_lossy(), to.to_string_lossy())
+            })?;
+            fs::remove_file(from)?;
+            Ok(())
         }
-        if aa.peek() == None {
-            return Ordering::Equal;
+        v => Ok(v?),
+    }
+}
+
+/// Where an Input reads from.
+#[derive(Debug)]
+enum Source {
+    File(BufReader<File>),
+    Stdin(BufReader<Stdin>),
+}
+
+impl Source {
+    fn get(&mut self) -> &mut dyn BufRead {
+        match self {
+            Source::File(v) => v,
+            Source::Stdin(v) => v,
         }
-        aa.next();
-        bb.next();
     }
 }
 
+/// Files, or stdin, read one after the other as if they were one. With
+/// nothing to read, it's empty.
 #[derive(Debug, Default)]
 pub struct Input {
-    file: Option<File>
+    sources: VecDeque<Source>,
 }
 
-// impl<'a> Input<'a> {
-//     pub fn new(f: Option<&Path>) -> Result<Self> {
-//         match f {
-//             Some(v) => Ok(Input::from(&File::open(v)?)),
-//             None => Ok(Input {
-//                 file: None
-//             })
-//         }
-//     }
-// }
+impl BufRead for Input {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        // Move on from sources that have run out.
+        while let Some(v) = self.sources.front_mut() {
+            if !v.get().fill_buf()?.is_empty() {
+                break;
+            }
+            self.sources.pop_front();
+        }
 
-impl Read for Input {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        match self.file.as_mut() {
-            Some(v) => v.read(buf),
-            None => io::stdin().read(buf)
+        match self.sources.front_mut() {
+            Some(v) => v.get().fill_buf(),
+            None => Ok(&[]),
+        }
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Some(v) = self.sources.front_mut() {
+            v.get().consume(amt);
         }
     }
 }
 
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data = self.fill_buf()?;
+        let len = data.len().min(buf.len());
+        buf[..len].copy_from_slice(&data[..len]);
+        self.consume(len);
+
+        Ok(len)
+    }
+}
+
 impl From<File> for Input {
     fn from(f: File) -> Self {
-        Input{
-            file: Some(f)
-        }
+        Some(f).into()
     }
 }
 
+/// None is stdin.
 impl From<Option<File>> for Input {
     fn from(f: Option<File>) -> Self {
-        Input { file: f }
+        iter::once(f).collect()
+    }
+}
+
+/// Each None is stdin.
+impl FromIterator<Option<File>> for Input {
+    fn from_iter<I: IntoIterator<Item = Option<File>>>(iter: I) -> Self {
+        let sources = iter.into_iter().map(|v| match v {
+            Some(v) => Source::File(BufReader::new(v)),
+            None => Source::Stdin(BufReader::new(io::stdin())),
+        });
+
+        Input { sources: sources.collect() }
     }
 }
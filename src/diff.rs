@@ -1,10 +1,26 @@
+#[allow(dead_code)]
+mod common;
+
+use crate::common::{devnull, Input};
+use toyrust::engine::{canon, diff, hunks, ignore_changes, trim_context, Diff, DiffOptions, Hunk, Line};
 use anyhow::{anyhow, Context, Result, bail};
-use clap::{Parser};
+use clap::{ArgEnum, Parser};
+use glob::Pattern;
+use regex::bytes::Regex;
+use std::borrow::Cow;
 use std::cmp::{Ordering};
 use std::convert::{TryFrom};
+use std::env;
+use std::io;
+use std::io::{BufRead, BufWriter, Write};
+use std::panic;
 use std::path::{Path, PathBuf};
 use std::fs;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::thread;
 use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// diff - compare files line by line
 #[derive(Default, Parser, Debug)]
@@ -14,6 +30,10 @@ struct Args {
     #[clap(short = 'a')]
     text: bool,
 
+    /// Output an ed script
+    #[clap(short = 'e')]
+    ed: bool,
+
     /// Ignore changes in the amount of whitespace
     #[clap(short = 'b')]
     ignore_space_change: bool,
@@ -22,27 +42,63 @@ struct Args {
     #[clap(short = 'B')]
     ignore_blank_lines: bool,
 
-    // Try hard to find a smaller set of changes
+    /// Output a context diff, with 3 lines of context
+    #[clap(short = 'c')]
+    context: bool,
+
+    /// Output a context diff with LINES lines of context
+    #[clap(short = 'C', value_name = "LINES")]
+    context_lines: Option<usize>,
+
+    /// Try hard to find a smaller set of changes
     #[clap(short = 'd')]
     minimal: bool,
 
+    /// Assume large files with small changes scattered through them, and
+    /// find a (maybe bigger) set of changes faster
+    #[clap(long)]
+    speed_large_files: bool,
+
+    /// Show the closest line above each hunk that matches RE in its header
+    #[clap(short = 'F', long = "show-function-line", value_name = "RE", number_of_values = 1, parse(try_from_str = Regex::new))]
+    show_function_line: Vec<Regex>,
+
+    /// Output the merged file, with the differences in #ifdef NAME
+    #[clap(short = 'D', long, value_name = "NAME")]
+    ifdef: Option<String>,
+
     /// Ignore case differences
     #[clap(short = 'i')]
     ignore_case: bool,
 
-    /// Use LABEL instead of the filename in the unified header
-    #[clap(short = 'L')]
-    label: Option<String>,
+    /// Ignore changes whose lines all match RE (or are blank, with -B)
+    #[clap(short = 'I', value_name = "RE", number_of_values = 1, parse(try_from_str = Regex::new))]
+    ignore_matching_lines: Vec<Regex>,
+
+    /// Use LABEL instead of the filename in the unified header (twice for
+    /// the second file)
+    #[clap(short = 'L', number_of_values = 1, max_occurrences = 2)]
+    label: Vec<String>,
 
     /// Treat absent files as empty
     #[clap(short = 'N')]
     new_file: bool,
 
+    /// Skip files and directories matching the shell PATTERN when comparing
+    /// directories
+    #[clap(short = 'x', long = "exclude", value_name = "PATTERN", number_of_values = 1, parse(try_from_str = Pattern::new))]
+    exclude: Vec<Pattern>,
+
+    /// Skip files matching any pattern in FILE, one per line
+    #[clap(short = 'X', long = "exclude-from", value_name = "FILE", number_of_values = 1)]
+    exclude_from: Vec<PathBuf>,
+
     /// Output only whether files differ
     #[clap(short = 'q')]
     brief: bool,
 
-    /// Recurse
+    /// Recurse into subdirectories. Entries are compared in the byte order
+    /// of their names, whatever the locale
     #[clap(short = 'r')]
     recurse: bool,
 
@@ -62,284 +118,1846 @@ struct Args {
     #[clap(short = 't')]
     expand_tabs: bool,
 
-    /// Output LINES lines of context
-    #[clap(short = 'U')]
-    unified: Option<i32>,
+    /// Output LINES lines of context (default 3)
+    #[clap(short = 'U', value_name = "LINES")]
+    unified: Option<usize>,
+
+    /// Ignore all whitespace
+    #[clap(short = 'w')]
+    ignore_all_space: bool,
+
+    /// Output at most NUM columns with -y (default 130)
+    #[clap(short = 'W', value_name = "NUM")]
+    width: Option<usize>,
+
+    /// Output the files side by side, in two columns
+    #[clap(short = 'y')]
+    side_by_side: bool,
+
+    /// Show which C function each hunk is in, like -F '^[[:alpha:]$_]'
+    #[clap(short = 'p', long)]
+    show_c_function: bool,
+
+    /// Color the output always, never, or auto (only on a terminal);
+    /// --color alone is always
+    #[clap(long, arg_enum, value_name = "WHEN", default_value = "never", default_missing_value = "always",
+        min_values = 0, max_values = 1, require_equals = true)]
+    color: When,
+
+    /// Colors to use, as ANSI codes for each part: file (file headers),
+    /// hdr (hunk headers), ins and del, like 'ins=32:del=31:hdr=36'
+    #[clap(long, value_name = "SPEC")]
+    palette: Option<Palette>,
+
+    /// Strip trailing '\r's from input lines
+    #[clap(long)]
+    strip_trailing_cr: bool,
+
+    /// Compare and output lines as if they all ended in LF, whether they
+    /// end in LF or CRLF in the files
+    #[clap(long, conflicts_with = "crlf")]
+    lf: bool,
+
+    /// Compare and output lines as if they all ended in CRLF, whether they
+    /// end in LF or CRLF in the files. Not with -y, where a '\r' would
+    /// end the left column halfway along the line
+    #[clap(long, conflicts_with = "side-by-side")]
+    crlf: bool,
+
+    /// Leave out context at either end of a hunk that -B or -I would
+    /// ignore
+    #[clap(long)]
+    minimal_context: bool,
+
+    /// Don't output lines that are the same in both files with -y
+    #[clap(long)]
+    suppress_common_lines: bool,
+
+    /// Show file names in headers as they are, even when they have spaces
+    /// or special characters in them
+    #[clap(long)]
+    no_quoting: bool,
+
+    /// Show how many lines of each file changed instead of the changes
+    #[clap(long)]
+    stat: bool,
+
+    /// Output both files merged, each line only in the first with FMT
+    #[clap(long, value_name = "FMT")]
+    old_line_format: Option<LineFormat>,
+
+    /// Output both files merged, each line only in the second with FMT
+    #[clap(long, value_name = "FMT")]
+    new_line_format: Option<LineFormat>,
+
+    /// Output both files merged, each line in both with FMT
+    #[clap(long, value_name = "FMT")]
+    unchanged_line_format: Option<LineFormat>,
+
+    /// Output both files merged, each line with FMT unless a format above
+    /// says otherwise (default '%l' and a newline). In FMT, %l is the line,
+    /// %L the line with its newline, %dn its number (also %on, %xn, %Xn,
+    /// with printf's - and 0 and a width, like %-5dn), %c'C' the character
+    /// C (or %c'\OOO' in octal), and %% a %
+    #[clap(long, value_name = "FMT")]
+    line_format: Option<LineFormat>,
+
+    /// Output the changes as json, one line for each pair of files
+    #[clap(long, arg_enum, value_name = "FORMAT")]
+    format: Option<Output>,
+
+    /// Take files with the same size and modification time to be the same
+    #[clap(long)]
+    brief_by_mtime: bool,
+
+    /// Tab stops every NUM columns, for -t and -y (default 8)
+    #[clap(long, value_name = "NUM", default_value = "8")]
+    tabsize: usize,
+
+    /// Compare symbolic links in directories by where they point, rather
+    /// than following them
+    #[clap(long)]
+    no_dereference: bool,
+
+    /// Compare the files in directories N at a time (default 1). The
+    /// output comes in the same order either way
+    #[clap(short = 'j', long, value_name = "N", default_value = "1")]
+    jobs: usize,
+
+    /// Pair up files in directories whose names differ only in case
+    #[clap(long)]
+    ignore_file_name_case: bool,
+
+    /// Compare FILE against each of the files given
+    #[clap(long, value_name = "FILE", conflicts_with = "to-file")]
+    from_file: Option<PathBuf>,
+
+    /// Compare each of the files given against FILE
+    #[clap(long, value_name = "FILE")]
+    to_file: Option<PathBuf>,
+
+    /// The two files to compare, or with --from-file or --to-file, any
+    /// number of them
+    #[clap(required = true, value_name = "FILE")]
+    files: Vec<PathBuf>,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Status {
+    #[default]
+    SAME,
+    DIFFER,
+    TROUBLE,
+}
+
+/// Output formats only picked by name, with --format.
+#[derive(ArgEnum, Clone, Copy, PartialEq, Debug)]
+enum Output {
+    Json,
+}
+
+/// When to color the output.
+#[derive(ArgEnum, Default, Clone, Copy, PartialEq, Debug)]
+enum When {
+    Always,
+    #[default]
+    Never,
+    Auto,
+}
+
+/// The ANSI SGR codes each part of colored output is drawn in.
+#[derive(Clone, PartialEq, Debug)]
+struct Palette {
+    file: String,
+    hdr: String,
+    ins: String,
+    del: String,
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette {
+            file: "1".to_string(),
+            hdr: "36".to_string(),
+            ins: "32".to_string(),
+            del: "31".to_string(),
+        }
+    }
+}
+
+/// Parse NAME=CODE pairs separated by ':', where CODE is numbers separated
+/// by ';'. Parts left out keep their default color.
+impl FromStr for Palette {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut palette = Palette::default();
+
+        for spec in s.split(':').filter(|v| !v.is_empty()) {
+            let (name, code) = spec
+                .split_once('=')
+                .ok_or_else(|| anyhow!("'{}' isn't NAME=CODE", spec))?;
+            if code.is_empty() || !code.split(';').all(|v| !v.is_empty() && v.bytes().all(|c| c.is_ascii_digit())) {
+                bail!("bad color '{}' for {}", code, name);
+            }
+
+            let role = match name {
+                "file" => &mut palette.file,
+                "hdr" => &mut palette.hdr,
+                "ins" => &mut palette.ins,
+                "del" => &mut palette.del,
+                _ => bail!("unknown palette entry '{}' (expected file, hdr, ins or del)", name),
+            };
+            *role = code.to_string();
+        }
+
+        Ok(palette)
+    }
+}
+
+/// One part of a --line-format.
+#[derive(Clone, PartialEq, Debug)]
+enum Piece {
+    Text(Vec<u8>),
+
+    /// %l, or %L with the newline.
+    Line { newline: bool },
+
+    /// %dn and the like: the line number, in `radix` (b'd', b'o', b'x' or
+    /// b'X'), padded to `width` on the left, or on the right with `left`.
+    Number { radix: u8, width: usize, left: bool, zero: bool },
+}
+
+/// How to output each line with --line-format and the like.
+#[derive(Clone, PartialEq, Debug)]
+struct LineFormat(Vec<Piece>);
+
+impl Default for LineFormat {
+    fn default() -> Self {
+        LineFormat(vec![Piece::Line { newline: false }, Piece::Text(b"\n".to_vec())])
+    }
+}
+
+impl FromStr for LineFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut pieces = vec![];
+        let mut text = vec![];
+        let mut s = s.bytes().peekable();
+
+        while let Some(c) = s.next() {
+            if c != b'%' {
+                text.push(c);
+                continue;
+            }
+
+            let piece = match s.next() {
+                Some(b'%') => {
+                    text.push(b'%');
+                    continue;
+                },
+                Some(b'l') => Piece::Line { newline: false },
+                Some(b'L') => Piece::Line { newline: true },
+                Some(b'c') => {
+                    if s.next() != Some(b'\'') {
+                        bail!("%c needs a quoted character, like %c'x'");
+                    }
+                    let c = match s.next() {
+                        Some(b'\\') => {
+                            let mut n: u32 = 0;
+                            for _ in 0..3 {
+                                match s.peek() {
+                                    Some(v @ b'0'..=b'7') => n = n * 8 + (v - b'0') as u32,
+                                    _ => break,
+                                }
+                                s.next();
+                            }
+                            u8::try_from(n).map_err(|_| anyhow!("%c'\\{:o}' is out of range", n))?
+                        },
+                        Some(v) => v,
+                        None => bail!("%c needs a quoted character, like %c'x'"),
+                    };
+                    if s.next() != Some(b'\'') {
+                        bail!("%c needs a quoted character, like %c'x'");
+                    }
+                    text.push(c);
+                    continue;
+                },
+                Some(mut c) => {
+                    let (mut left, mut zero, mut width) = (false, false, 0);
+                    while c == b'-' || c == b'0' {
+                        left |= c == b'-';
+                        zero |= c == b'0';
+                        c = s.next().unwrap_or_default();
+                    }
+                    while c.is_ascii_digit() {
+                        width = width * 10 + (c - b'0') as usize;
+                        c = s.next().unwrap_or_default();
+                    }
+                    if !matches!(c, b'd' | b'o' | b'x' | b'X') || s.next() != Some(b'n') {
+                        bail!("unknown escape in line format (expected %l, %L, %dn, %c'C' or %%)");
+                    }
+                    Piece::Number { radix: c, width, left, zero }
+                },
+                None => bail!("line format ends in %"),
+            };
+
+            if !text.is_empty() {
+                pieces.push(Piece::Text(std::mem::take(&mut text)));
+            }
+            pieces.push(piece);
+        }
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+
+        Ok(LineFormat(pieces))
+    }
+}
+
+impl LineFormat {
+    /// Output line number `n`, which has a newline after it unless `eol`
+    /// says otherwise.
+    fn write(&self, out: &mut impl Write, line: &[u8], n: usize, eol: bool) -> Result<()> {
+        for piece in &self.0 {
+            match piece {
+                Piece::Text(v) => out.write_all(v)?,
+                Piece::Line { newline } => {
+                    out.write_all(line)?;
+                    if *newline && eol {
+                        out.write_all(b"\n")?;
+                    }
+                },
+                Piece::Number { radix, width, left, zero } => {
+                    let n = match radix {
+                        b'o' => format!("{:o}", n),
+                        b'x' => format!("{:x}", n),
+                        b'X' => format!("{:X}", n),
+                        _ => n.to_string(),
+                    };
+                    let pad = width.saturating_sub(n.len());
+                    match (left, zero) {
+                        (true, _) => write!(out, "{}{}", n, " ".repeat(pad))?,
+                        (false, true) => write!(out, "{}{}", "0".repeat(pad), n)?,
+                        (false, false) => write!(out, "{}{}", " ".repeat(pad), n)?,
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How the differences are shown.
+#[derive(Default, Clone, Copy, PartialEq)]
+enum Format {
+    #[default]
+    Unified,
+    Context,
+    Ed,
+    SideBySide,
+    Ifdef,
+    Lines,
+    Stat,
+    Json,
+}
+
+/// State shared across the whole comparison.
+#[derive(Default)]
+struct Globals {
+    /// Command line options.
+    optflags: Args,
+
+    /// How the diff engine compares lines, from optflags.
+    opts: DiffOptions,
+
+    /// How the differences are shown, from optflags.
+    format: Format,
+
+    /// Whether the current pair of files is binary.
+    is_binary: bool,
+
+    /// The line endings of the current pair of files, as far as they were
+    /// read.
+    endings: [Endings; 2],
+
+    /// Result of the last comparison.
+    status: Status,
+
+    /// Some pair of files in a directory differed, whatever the last did.
+    differed: bool,
+
+    /// Something went wrong that didn't stop the comparison, like a loop
+    /// in a directory walk, so the exit status says there was trouble.
+    trouble: bool,
+
+    /// With --stat, each file that differed and the lines added to and
+    /// removed from it, or None if it's binary.
+    stat: Vec<(PathBuf, Option<(usize, usize)>)>,
+
+    /// Length of the root paths for each dir entry.
+    len: [PathBuf; 2],
+
+    /// Metadata of the two operands.
+    st: [Metadata; 2],
+
+    /// Patterns of names to leave out of directory comparisons, from -x
+    /// and -X.
+    exclude: Vec<Pattern>,
+
+    /// Lines to show in hunk headers, from -F and -p.
+    function: Vec<Regex>,
+
+    /// Colors to use, from --palette.
+    palette: Palette,
+
+    /// How to output lines only in the first file, only in the second, and
+    /// in both, from --line-format and the like.
+    line_format: [LineFormat; 3],
+
+    /// List of directories and files under the specified paths.
+    dir: [Vec<walkdir::DirEntry>; 2],
+
+    /// The next pair of files in a directory, already compared with -j.
+    prepared: Option<([PathBuf; 2], Compared)>,
+}
+
+impl From<&Args> for DiffOptions {
+    fn from(flags: &Args) -> Self {
+        let opts = DiffOptions::new()
+            .ignore_case(flags.ignore_case)
+            .ignore_space_change(flags.ignore_space_change)
+            .ignore_all_space(flags.ignore_all_space)
+            .ignore_blank_lines(flags.ignore_blank_lines)
+            .minimal(flags.minimal)
+            .speed_large_files(flags.speed_large_files)
+            .context(flags.unified.or(flags.context_lines).unwrap_or(3))
+            .minimal_context(flags.minimal_context);
+        flags.ignore_matching_lines.iter().cloned().fold(opts, DiffOptions::ignore_matching_lines)
+    }
+}
+
+impl From<&Args> for Format {
+    fn from(flags: &Args) -> Self {
+        if flags.format == Some(Output::Json) {
+            Format::Json
+        } else if flags.stat {
+            Format::Stat
+        } else if flags.ed {
+            Format::Ed
+        } else if flags.side_by_side {
+            Format::SideBySide
+        } else if flags.ifdef.is_some() {
+            Format::Ifdef
+        } else if flags.line_format.is_some() || flags.old_line_format.is_some()
+            || flags.new_line_format.is_some() || flags.unchanged_line_format.is_some() {
+            Format::Lines
+        } else if flags.context || flags.context_lines.is_some() {
+            Format::Context
+        } else {
+            Format::Unified
+        }
+    }
+}
+
+impl Format {
+    /// Whether the output includes the lines the files have in common, so
+    /// there's something to show even when they're the same.
+    fn shows_common(self) -> bool {
+        matches!(self, Format::SideBySide | Format::Ifdef | Format::Lines)
+    }
+}
+
+impl Globals {
+    /// Whether a directory entry's name, or its path under the directory
+    /// being compared, matches an -x or -X pattern.
+    fn excluded(&self, root: &Path, entry: &walkdir::DirEntry) -> bool {
+        let rel = entry.path().strip_prefix(root).unwrap_or_else(|_| entry.path());
+        let name = entry.file_name().to_string_lossy();
+
+        self.exclude.iter().any(|p| p.matches(&name) || p.matches_path(rel))
+    }
+
+    /// Metadata of a file in a directory being compared, or of the link
+    /// itself with --no-dereference.
+    fn stat(&self, path: &PathBuf) -> Result<Metadata> {
+        match self.optflags.no_dereference {
+            true => Ok(Metadata {
+                metadata: Some(fs::symlink_metadata(path).with_context(|| path.to_string_lossy().to_string())?),
+            }),
+            false => Metadata::try_from(path),
+        }
+    }
+
+    /// The ANSI color for the part of the output `role` picks out of the
+    /// palette, if output is being colored.
+    fn color(&self, role: impl Fn(&Palette) -> &String) -> Option<&str> {
+        match self.optflags.color {
+            When::Always => Some(role(&self.palette)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+struct Metadata {
+    metadata: Option<fs::Metadata>
+}
+
+/// The lines of one of the files being compared.
+#[derive(Default)]
+struct Contents {
+    lines: Vec<Vec<u8>>,
+
+    /// The last line has no trailing newline.
+    missing_eol: bool,
+
+    /// The line endings the lines had before --strip-trailing-cr, --lf or
+    /// --crlf.
+    endings: Endings,
+}
+
+/// How many lines of a file end in LF, and how many in CRLF.
+#[derive(Clone, Copy, Default)]
+struct Endings {
+    lf: usize,
+    crlf: usize,
+}
+
+impl Endings {
+    /// Which ending most of the lines have, "LF" or "CRLF", or None if
+    /// none had either.
+    fn most(&self) -> Option<&'static str> {
+        match (self.lf, self.crlf) {
+            (0, 0) => None,
+            (lf, crlf) if crlf > lf => Some("CRLF"),
+            _ => Some("LF"),
+        }
+    }
+}
+
+impl Metadata {
+    fn is_dir(&self) -> bool {
+        match &self.metadata {
+            Some(v) => v.is_dir(),
+            None => false
+        }
+    }
+
+    fn is_file(&self) -> bool {
+        match &self.metadata {
+            Some(v) => v.is_file(),
+            None => false
+        }
+    }
+
+    fn is_symlink(&self) -> bool {
+        match &self.metadata {
+            Some(v) => v.file_type().is_symlink(),
+            None => false
+        }
+    }
+
+    /// A device, socket or pipe: something there, but not a file to read
+    /// lines from.
+    fn is_special(&self) -> bool {
+        self.metadata.is_some() && !self.is_file() && !self.is_dir() && !self.is_symlink()
+    }
+
+    /// What sort of file this is, for messages.
+    fn kind(&self) -> &'static str {
+        if self.is_symlink() {
+            "symbolic link"
+        } else if self.is_dir() {
+            "directory"
+        } else if self.is_file() {
+            "regular file"
+        } else if self.is_special() {
+            self.special_kind()
+        } else {
+            "nonexistent file"
+        }
+    }
+
+    #[cfg(unix)]
+    fn special_kind(&self) -> &'static str {
+        use std::os::unix::fs::FileTypeExt;
+
+        match self.metadata.as_ref().map(|v| v.file_type()) {
+            Some(v) if v.is_fifo() => "fifo",
+            Some(v) if v.is_char_device() => "character special file",
+            Some(v) if v.is_block_device() => "block special file",
+            Some(v) if v.is_socket() => "socket",
+            _ => "special file",
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn special_kind(&self) -> &'static str {
+        "special file"
+    }
+
+    fn len(&self) -> Option<u64> {
+        self.metadata.as_ref().map(|v| v.len())
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        self.metadata.as_ref().and_then(|v| v.modified().ok())
+    }
+
+    /// The device and inode, which are the same only for the same file.
+    #[cfg(unix)]
+    fn id(&self) -> Option<(u64, u64)> {
+        use std::os::unix::fs::MetadataExt;
+
+        self.metadata.as_ref().map(|v| (v.dev(), v.ino()))
+    }
+}
+
+impl TryFrom<&PathBuf> for Metadata {
+    type Error = anyhow::Error;
+    fn try_from(p: &PathBuf) -> Result<Metadata, Self::Error> {
+        Ok(Metadata{metadata: Some(fs::metadata(p).with_context(|| p.to_string_lossy().to_string())?)})
+    }
+}
+
+
+/// Metadata of a file named on the command line. With -N, one that doesn't
+/// exist has none, and reads as empty.
+fn stat_operand(path: &PathBuf, new_file: bool) -> Result<Metadata> {
+    match fs::metadata(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => match new_file {
+            true => Ok(Default::default()),
+            false => bail!("{}: No such file or directory", path.to_string_lossy()),
+        },
+        _ => Metadata::try_from(path),
+    }
+}
+
+fn is_a_tty(stderr: bool) -> bool {
+    let stream = if stderr {
+        atty::Stream::Stderr
+    } else {
+        atty::Stream::Stdout
+    };
+
+    atty::is(stream)
+}
+
+fn is_stdin(p: &Path) -> bool {
+    p.to_string_lossy() == "-"
+}
+
+/// Whether this is a pipe the shell handed over by name, like the
+/// /dev/fd/N of `<(command)`, which has something writing to it.
+fn is_shell_pipe(p: &Path) -> bool {
+    p.starts_with("/dev/fd") || p.starts_with("/proc/self/fd")
+}
+
+/// Whether the two operands are physically the same file: the same inode
+/// on the same device on Unix, or the same canonical path elsewhere. Only
+/// files and directories count: a pipe, like the /dev/fd/N of a shell's
+/// `<(command)`, is read through whatever it is, once.
+#[cfg_attr(unix, allow(unused_variables))]
+fn same_file(files: &[PathBuf], st: &[Metadata; 2]) -> bool {
+    if st.iter().any(|v| !v.is_file() && !v.is_dir()) {
+        return false;
+    }
+
+    #[cfg(unix)]
+    return st[0].id().is_some() && st[0].id() == st[1].id();
+
+    #[cfg(not(unix))]
+    return !files.iter().any(|v| is_stdin(v))
+        && matches!((fs::canonicalize(&files[0]), fs::canonicalize(&files[1])), (Ok(a), Ok(b)) if a == b);
+}
+
+/// Read the next line into `line`, without its terminator, counting its
+/// ending in `endings`. Returns whether the line ended in a newline, or
+/// None at end of file.
+fn next_line(fp: &mut impl BufRead, line: &mut Vec<u8>, endings: &mut Endings, flags: &Args)
+    -> Result<Option<bool>> {
+    line.clear();
+    if fp.read_until(b'\n', line)? == 0 {
+        return Ok(None);
+    }
+    let eol = line.last() == Some(&b'\n');
+    if eol {
+        line.pop();
+        match line.last() == Some(&b'\r') {
+            true => endings.crlf += 1,
+            false => endings.lf += 1,
+        }
+    }
+    if (flags.strip_trailing_cr || flags.lf || flags.crlf) && line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    if flags.crlf && eol {
+        line.push(b'\r');
+    }
+
+    Ok(Some(eol))
+}
+
+/// Open a file for reading, or stdin if it is "-".
+fn open_input(path: &Path) -> Result<Input> {
+    let fp = match is_stdin(path) {
+        true => None,
+        false => Some(fs::File::open(path)?),
+    };
+
+    Ok(Input::from(fp))
+}
+
+/// Read a file into memory, split into lines without their terminators.
+fn read_lines(path: &Path, flags: &Args) -> Result<Contents> {
+    let mut fp = open_input(path)?;
+    let mut file: Contents = Default::default();
+    let mut line = vec![];
+
+    while let Some(eol) = next_line(&mut fp, &mut line, &mut file.endings, flags)? {
+        file.lines.push(line.clone());
+        file.missing_eol = !eol;
+    }
+
+    Ok(file)
+}
+
+/// Guess whether a file is binary, from a NUL byte in its first few KB.
+fn is_binary(file: &Contents) -> bool {
+    let mut len = 0;
+
+    for line in &file.lines {
+        if len >= 8192 {
+            break;
+        }
+        if line.contains(&0) {
+            return true;
+        }
+        len += line.len() + 1;
+    }
+
+    false
+}
+
+/// Compare both files line by line, giving up at the first difference.
+/// This is all brief mode needs, so it skips the edit script entirely, and
+/// counts only the line endings it got as far as.
+fn same_lines(files: &[PathBuf], endings: &mut [Endings; 2], flags: &Args, opts: &DiffOptions)
+    -> Result<bool> {
+    let mut fp = [open_input(&files[0])?, open_input(&files[1])?];
+    let mut line: [Vec<u8>; 2] = Default::default();
+
+    loop {
+        let more = next_line(&mut fp[0], &mut line[0], &mut endings[0], flags)?;
+        if more != next_line(&mut fp[1], &mut line[1], &mut endings[1], flags)?
+            || canon(&line[0], opts) != canon(&line[1], opts)
+        {
+            return Ok(false);
+        }
+        if more.is_none() {
+            return Ok(true);
+        }
+    }
+}
+
+/// Format a timestamp the way the header expects it, in UTC: unified diffs
+/// use `%Y-%m-%d %H:%M:%S.%N %z`, and context diffs `%a %b %e %T %Y`.
+fn format_time(t: SystemTime, format: Format) -> String {
+    let t = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = t.as_secs();
+    let rem = secs % 86400;
+
+    // Days since the epoch to a civil date, proleptic Gregorian calendar.
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    if format == Format::Context {
+        const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+        const MONTHS: [&str; 12] = [
+            "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+        ];
+
+        return format!(
+            "{} {} {:2} {:02}:{:02}:{:02} {}",
+            DAYS[(secs / 86400 % 7) as usize],
+            MONTHS[month as usize - 1],
+            day,
+            rem / 3600,
+            rem / 60 % 60,
+            rem % 60,
+            year
+        );
+    }
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:09} +0000",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        t.subsec_nanos()
+    )
+}
+
+/// A file name as it goes in a header. With `quote`, a name with spaces,
+/// quotes, backslashes, control characters or anything outside ASCII goes
+/// in double quotes with C escapes, like git does.
+fn quote_name(path: &Path, quote: bool) -> String {
+    #[cfg(unix)]
+    let name = {
+        use std::os::unix::ffi::OsStrExt;
+
+        path.as_os_str().as_bytes().to_vec()
+    };
+    #[cfg(not(unix))]
+    let name = path.to_string_lossy().into_owned().into_bytes();
+
+    let plain = |c: &u8| c.is_ascii_graphic() && *c != b'"' && *c != b'\\';
+    if !quote || name.iter().all(plain) {
+        return path.to_string_lossy().into_owned();
+    }
+
+    let mut out = String::from("\"");
+    for c in name {
+        match c {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\t' => out.push_str("\\t"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            0x07 => out.push_str("\\a"),
+            0x08 => out.push_str("\\b"),
+            0x0b => out.push_str("\\v"),
+            0x0c => out.push_str("\\f"),
+            c if c == b' ' || c.is_ascii_graphic() => out.push(c as char),
+            c => out.push_str(&format!("\\{:03o}", c)),
+        }
+    }
+    out.push('"');
+
+    out
+}
+
+/// The name and modification time of a file, as shown in the header.
+fn show_label(path: &Path, st: &Metadata, format: Format, quote: bool) -> Result<String> {
+    // No metadata is either stdin, or a file -N is treating as empty.
+    let mtime = match &st.metadata {
+        Some(v) => v.modified()?,
+        None if is_stdin(path) => SystemTime::now(),
+        None => UNIX_EPOCH,
+    };
+
+    Ok(format!("{}\t{}", quote_name(path, quote), format_time(mtime, format)))
+}
+
+/// Write one line of output made of `parts`, wrapped in the ANSI color
+/// `code` if there is one.
+fn paint(out: &mut impl Write, code: Option<&str>, parts: &[&[u8]]) -> Result<()> {
+    if let Some(v) = code {
+        write!(out, "\x1b[{}m", v)?;
+    }
+    for part in parts {
+        out.write_all(part)?;
+    }
+    if code.is_some() {
+        out.write_all(b"\x1b[0m")?;
+    }
+    out.write_all(b"\n")?;
+
+    Ok(())
+}
+
+/// Print one line of a hunk behind its `marker`, in the color `code` with
+/// the byte ranges `emph` of it in reverse video, followed by the missing
+/// newline marker if it's the last line of a file that doesn't end in one.
+#[allow(non_snake_case)]
+fn print_line(
+    out: &mut impl Write,
+    TT: &Globals,
+    marker: u8,
+    code: Option<&str>,
+    line: &[u8],
+    emph: &[(usize, usize)],
+    missing_eol: bool,
+) -> Result<()> {
+    // Context diffs put a space after the marker. -T makes that a tab, and
+    // replaces the gutter of unified context lines with one.
+    let prefix: &[u8] = match (TT.format, TT.optflags.initial_tab, marker) {
+        (Format::Context, false, _) => &[marker, b' '],
+        (Format::Context, true, _) => &[marker, b'\t'],
+        (_, false, _) => &[marker],
+        (_, true, b' ') => b"\t",
+        (_, true, b'-') => b"-\t",
+        (_, true, _) => b"+\t",
+    };
+
+    let text = match TT.optflags.expand_tabs {
+        true => Cow::Owned(expand_tabs(line, TT.optflags.tabsize)),
+        false => Cow::Borrowed(line),
+    };
+
+    // Where the ranges start and end once tabs are expanded.
+    let at = |i: usize| match TT.optflags.expand_tabs {
+        true => expand_tabs(&line[..i], TT.optflags.tabsize).len(),
+        false => i,
+    };
+    let mut parts: Vec<&[u8]> = vec![prefix];
+    let mut last = 0;
+    for &(start, end) in emph.iter().filter(|_| code.is_some()) {
+        let (start, end) = (at(start), at(end));
+        parts.extend_from_slice(&[&text[last..start], b"\x1b[7m", &text[start..end], b"\x1b[27m"]);
+        last = end;
+    }
+    parts.push(&text[last..]);
+    paint(out, code, &parts)?;
+
+    if missing_eol {
+        writeln!(out, "\\ No newline at end of file")?;
+    }
+
+    Ok(())
+}
+
+/// Expand tabs to spaces, with a tab stop every `tabsize` columns counted
+/// from the start of the line's text (the gutter doesn't count).
+fn expand_tabs(line: &[u8], tabsize: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(line.len());
+    let mut col = 0;
+
+    for &c in line {
+        if c == b'\t' {
+            let n = tabsize - col % tabsize;
+            out.resize(out.len() + n, b' ');
+            col += n;
+        } else {
+            out.push(c);
+            // Don't count UTF-8 continuation bytes as columns.
+            if c & 0xc0 != 0x80 {
+                col += 1;
+            }
+        }
+    }
+
+    out
+}
+
+/// Format one side of a unified hunk header. An empty range is reported
+/// as the line before it, and a single line omits its length.
+fn hunk_range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start - 1),
+        1 => format!("{}", start),
+        n => format!("{},{}", start, n),
+    }
+}
+
+/// Two files read in, and the changes from one to the other, or None if
+/// they're binary.
+#[derive(Default)]
+struct Compared {
+    file: [Contents; 2],
+    d: Option<Vec<Diff>>,
+}
+
+/// Read two files and work out the changes between them. This is the slow
+/// part of do_diff, which -j does for several files at once, so it only
+/// reads TT.
+#[allow(non_snake_case)]
+fn compare(files: &[PathBuf], TT: &Globals) -> Result<Compared> {
+    let file = [
+        read_lines(&files[0], &TT.optflags)?,
+        read_lines(&files[1], &TT.optflags)?,
+    ];
+
+    // Don't spew binary data at the terminal unless -a asked for it.
+    if !TT.optflags.text && (is_binary(&file[0]) || is_binary(&file[1])) {
+        return Ok(Compared { file, d: None });
+    }
+
+    let lines = [&file[0], &file[1]].map(|f| {
+        f.lines.iter().map(Vec::as_slice).collect::<Vec<_>>()
+    });
+
+    let keys = [&file[0], &file[1]].map(|f| {
+        let mut keys = f.lines
+            .iter()
+            .map(|l| canon(l, &TT.opts))
+            .collect::<Vec<_>>();
+
+        // A last line missing its newline never matches one that has it.
+        if f.missing_eol {
+            if let Some(v) = keys.last_mut() {
+                v.to_mut().push(b'\n');
+            }
+        }
+
+        keys
+    });
+
+    let mut d = diff(&keys[0], &keys[1], &TT.opts);
+
+    if TT.opts.ignore_blank_lines || !TT.opts.ignore_matching_lines.is_empty() {
+        ignore_changes(&mut d, &lines[0], &lines[1], &TT.opts);
+    }
+
+    Ok(Compared { file, d: Some(d) })
+}
+
+#[allow(non_snake_case)]
+fn do_diff(files: &[PathBuf], names: &[PathBuf], TT: &mut Globals) -> Result<()> {
+    // Ignoring blank or matching lines can pair up lines at different
+    // offsets, which a line by line comparison can't see.
+    TT.is_binary = false;
+    TT.endings = Default::default();
+
+    // mtime isn't to be trusted, but --brief-by-mtime says it is, and then
+    // there's no need to read the files.
+    let [a, b] = &TT.st;
+    if TT.optflags.brief_by_mtime && !TT.format.shows_common() && a.is_file() && b.is_file()
+        && a.len() == b.len() && a.modified().is_some() && a.modified() == b.modified() {
+        TT.status = Status::SAME;
+        return Ok(());
+    }
+
+    if TT.optflags.brief && !TT.opts.ignore_blank_lines && TT.opts.ignore_matching_lines.is_empty() {
+        TT.status = match same_lines(files, &mut TT.endings, &TT.optflags, &TT.opts)? {
+            true => Status::SAME,
+            false => Status::DIFFER,
+        };
+        return Ok(());
+    }
+
+    // -j may have compared the files already.
+    let Compared { file, d } = match TT.prepared.take() {
+        Some((v, c)) if v == files => c,
+        _ => compare(files, TT)?,
+    };
+
+    TT.is_binary = d.is_none();
+    TT.endings = [file[0].endings, file[1].endings];
+    if TT.is_binary {
+        TT.status = match file[0].lines == file[1].lines && file[0].missing_eol == file[1].missing_eol {
+            true => Status::SAME,
+            false => Status::DIFFER,
+        };
+        if TT.format == Format::Stat && TT.status == Status::DIFFER {
+            TT.stat.push((names[1].clone(), None));
+        }
+        if TT.format == Format::Json && TT.status == Status::DIFFER {
+            println!("{{\"old\":{},\"new\":{},\"binary\":true}}",
+                json_string(json_name(TT, names, 0).as_bytes()), json_string(json_name(TT, names, 1).as_bytes()));
+        }
+        return Ok(());
+    }
+
+    let lines = [&file[0], &file[1]].map(|f| {
+        f.lines.iter().map(Vec::as_slice).collect::<Vec<_>>()
+    });
+    let d = d.unwrap_or_default();
+
+    // Changes -B ignores don't make the files differ.
+    TT.status = match d.iter().all(|t| t.ignore) {
+        true => Status::SAME,
+        false => Status::DIFFER,
+    };
+
+    if TT.optflags.brief || (TT.status == Status::SAME && !TT.format.shows_common()) {
+        return Ok(());
+    }
+
+    // --stat only counts lines, to print once every file's been compared.
+    if TT.format == Format::Stat {
+        let changed = d.iter().filter(|t| !t.ignore);
+        let added = changed.clone().map(|t| t.d + 1 - t.c).sum();
+        let removed = changed.map(|t| t.b + 1 - t.a).sum();
+        TT.stat.push((names[1].clone(), Some((added, removed))));
+        return Ok(());
+    }
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+
+    match TT.format {
+        Format::Unified => print_unified(&mut out, TT, names, &file, &lines, &d)?,
+        Format::Context => print_context(&mut out, TT, names, &file, &lines, &d)?,
+        Format::Ed => print_ed(&mut out, &lines[1], &d)?,
+        Format::SideBySide => print_side_by_side(&mut out, TT, &file, &lines, &d)?,
+        Format::Ifdef => print_ifdef(&mut out, TT, &file, &lines, &d)?,
+        Format::Lines => print_lines(&mut out, TT, &file, &lines, &d)?,
+        Format::Json => print_json(&mut out, TT, names, &file, &lines, &d)?,
+        Format::Stat => {},
+    }
+
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Finds the line to show in each hunk header with -F or -p: the closest
+/// one above the hunk that matches. Hunks come in order, so each search
+/// only goes back as far as the last, and if it finds nothing the hunk is
+/// still in the function found then.
+struct FunctionFinder<'a, 'b> {
+    lines: &'b [&'a [u8]],
+    re: &'b [Regex],
+    searched: usize,
+    found: Option<&'a [u8]>,
+}
+
+impl<'a, 'b> FunctionFinder<'a, 'b> {
+    fn new(lines: &'b [&'a [u8]], re: &'b [Regex]) -> Self {
+        FunctionFinder { lines, re, searched: 0, found: None }
+    }
+
+    /// The text to add to the header of a hunk starting at line `start`:
+    /// up to 40 bytes of the function line, trimmed, after a space.
+    fn header(&mut self, start: usize) -> Vec<u8> {
+        let end = start.saturating_sub(1).min(self.lines.len());
+        let above = &self.lines[self.searched.min(end)..end];
+        if let Some(v) = above.iter().rev().find(|l| self.re.iter().any(|r| r.is_match(l))) {
+            self.found = Some(v);
+        }
+        self.searched = self.searched.max(end);
+
+        let line = match self.found {
+            Some(v) => v,
+            None => return vec![],
+        };
+        let line = &line[line.iter().take_while(|c| c.is_ascii_whitespace()).count()..];
+        let mut line = &line[..line.len().min(40)];
+        while let Some((c, rest)) = line.split_last() {
+            match c.is_ascii_whitespace() {
+                true => line = rest,
+                false => break,
+            }
+        }
+
+        [b" ", line].concat()
+    }
+}
+
+/// Split a line into words, runs of spaces, and single other bytes, as
+/// byte ranges.
+fn words(line: &[u8]) -> Vec<(usize, usize)> {
+    let class = |c: u8| match c {
+        c if c.is_ascii_alphanumeric() || c == b'_' || c >= 0x80 => 0,
+        c if c.is_ascii_whitespace() => 1,
+        _ => 2,
+    };
+    let mut out = vec![];
+    let mut i = 0;
+
+    while i < line.len() {
+        let mut j = i + 1;
+        if class(line[i]) != 2 {
+            while j < line.len() && class(line[j]) == class(line[i]) {
+                j += 1;
+            }
+        }
+        out.push((i, j));
+        i = j;
+    }
+
+    out
+}
+
+/// For each line of a hunk, the byte ranges of it that changed. Lines
+/// removed and then added in one run pair up in order, and a pair that's
+/// at least half the same, counting words, shows which words differ.
+fn word_changes(lines: &[Line<[u8]>]) -> Vec<Vec<(usize, usize)>> {
+    let mut emph = vec![vec![]; lines.len()];
+    let mut i = 0;
+
+    while i < lines.len() {
+        let removed = lines[i..].iter().take_while(|l| matches!(l, Line::Remove(_))).count();
+        let added = lines[i + removed..].iter().take_while(|l| matches!(l, Line::Add(_))).count();
+        if removed == 0 || added == 0 {
+            i += removed.max(1);
+            continue;
+        }
+
+        for n in 0..removed.min(added) {
+            let (a, b) = match (&lines[i + n], &lines[i + removed + n]) {
+                (Line::Remove(a), Line::Add(b)) => (*a, *b),
+                _ => unreachable!(),
+            };
+            let (wa, wb) = (words(a), words(b));
+            let ta: Vec<&[u8]> = wa.iter().map(|&(s, e)| &a[s..e]).collect();
+            let tb: Vec<&[u8]> = wb.iter().map(|&(s, e)| &b[s..e]).collect();
+
+            let d = diff(&ta, &tb, &DiffOptions::default());
+            let changed: usize = d.iter().filter(|v| v.a <= v.b).map(|v| wa[v.b - 1].1 - wa[v.a - 1].0).sum();
+            if 2 * (a.len() - changed) < a.len().max(b.len()) {
+                continue;
+            }
+
+            for v in &d {
+                if v.a <= v.b {
+                    emph[i + n].push((wa[v.a - 1].0, wa[v.b - 1].1));
+                }
+                if v.c <= v.d {
+                    emph[i + removed + n].push((wb[v.c - 1].0, wb[v.d - 1].1));
+                }
+            }
+        }
+        i += removed + added;
+    }
+
+    emph
+}
+
+/// Group the changes into hunks to show, trimmed with --minimal-context.
+#[allow(non_snake_case)]
+fn show_hunks<'a>(TT: &Globals, lines: &[Vec<&'a [u8]>; 2], d: &[Diff]) -> Vec<Hunk<'a, [u8]>> {
+    let mut hunks = hunks(&lines[0], &lines[1], d, TT.opts.context);
+    if TT.opts.minimal_context {
+        hunks.iter_mut().for_each(|h| trim_context(h, &TT.opts));
+    }
+
+    hunks
+}
+
+/// Print the changes as a unified diff: both file names, then each hunk of
+/// changes with its context.
+#[allow(non_snake_case)]
+fn print_unified(
+    out: &mut impl Write,
+    TT: &Globals,
+    names: &[PathBuf],
+    file: &[Contents; 2],
+    lines: &[Vec<&[u8]>; 2],
+    d: &[Diff],
+) -> Result<()> {
+    for (i, prefix) in [b"--- ", b"+++ "].iter().enumerate() {
+        let label = match TT.optflags.label.get(i) {
+            Some(v) => v.clone(),
+            None => show_label(&names[i], &TT.st[i], TT.format, !TT.optflags.no_quoting)?,
+        };
+        paint(out, TT.color(|p| &p.file), &[*prefix, label.as_bytes()])?;
+    }
+
+    let mut function = FunctionFinder::new(&lines[0], &TT.function);
+
+    for h in &show_hunks(TT, lines, d) {
+        let header = format!("@@ -{} +{} @@", hunk_range(h.old_start, h.old_len), hunk_range(h.new_start, h.new_len));
+        paint(out, TT.color(|p| &p.hdr), &[header.as_bytes(), &function.header(h.old_start)])?;
+
+        // Track where each line sits in its file, to spot the last one.
+        let (mut x, mut y) = (h.old_start, h.new_start);
+
+        // Picking out the words that changed in a line takes color to show.
+        let emph = match TT.color(|p| &p.del).is_some() {
+            true => word_changes(&h.lines),
+            false => vec![vec![]; h.lines.len()],
+        };
+
+        for (n, l) in h.lines.iter().enumerate() {
+            let (marker, line, last) = match *l {
+                Line::Context(v) => {
+                    x += 1;
+                    y += 1;
+                    (b' ', v, x > file[0].lines.len() && file[0].missing_eol)
+                },
+                Line::Remove(v) => {
+                    x += 1;
+                    (b'-', v, x > file[0].lines.len() && file[0].missing_eol)
+                },
+                Line::Add(v) => {
+                    y += 1;
+                    (b'+', v, y > file[1].lines.len() && file[1].missing_eol)
+                },
+            };
+            let code = match marker {
+                b'-' => TT.color(|p| &p.del),
+                b'+' => TT.color(|p| &p.ins),
+                _ => None,
+            };
+            print_line(out, TT, marker, code, line, &emph[n], last)?;
+        }
+    }
+
+    Ok(())
+}
 
-    /// Ignore all whitespace
-    #[clap(short = 'w')]
-    ignore_all_space: bool,
+/// Quote bytes as a json string. Lines that aren't UTF-8 can't be told
+/// apart in json, so they're read the lossy way.
+fn json_string(s: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for c in String::from_utf8_lossy(s).chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
 
-    /// Colored output
-    #[clap(long)]
-    color: bool,
+    out
+}
 
-    /// Strip trailing '\r's from input lines
-    #[clap(long)]
-    strip_trailing_cr: bool,
+/// The name to give file `i` in json output: its -L label if it has one.
+#[allow(non_snake_case)]
+fn json_name(TT: &Globals, names: &[PathBuf], i: usize) -> String {
+    match TT.optflags.label.get(i) {
+        Some(v) => v.clone(),
+        None => names[i].to_string_lossy().into_owned(),
+    }
+}
 
-    /// File to be compared against
-    #[clap()]
-    file1: PathBuf,
+/// Print the changes as one line of json, for tools to read instead of a
+/// unified diff. The schema is
+///
+/// ```text
+/// {"old": NAME, "new": NAME, "hunks": [
+///   {"old_start": N, "old_len": N, "new_start": N, "new_len": N,
+///    "lines": [{"type": "context" | "delete" | "insert", "text": TEXT,
+///               "no_newline": true}, ...]},
+///   ...]}
+/// ```
+///
+/// Starts are 1-based, and an empty range starts at the line after it
+/// (unlike in a unified hunk header, which names the line before).
+/// `no_newline` is only on the last line of a file that doesn't end in
+/// one. Binary files that differ get
+/// `{"old": NAME, "new": NAME, "binary": true}` instead, and a file only in
+/// one directory `{"only_in": DIR, "name": NAME}`.
+#[allow(non_snake_case)]
+fn print_json(
+    out: &mut impl Write,
+    TT: &Globals,
+    names: &[PathBuf],
+    file: &[Contents; 2],
+    lines: &[Vec<&[u8]>; 2],
+    d: &[Diff],
+) -> Result<()> {
+    write!(out, "{{\"old\":{},\"new\":{},\"hunks\":[",
+        json_string(json_name(TT, names, 0).as_bytes()), json_string(json_name(TT, names, 1).as_bytes()))?;
+
+    for (i, h) in show_hunks(TT, lines, d).iter().enumerate() {
+        if i > 0 {
+            write!(out, ",")?;
+        }
+        write!(out, "{{\"old_start\":{},\"old_len\":{},\"new_start\":{},\"new_len\":{},\"lines\":[",
+            h.old_start, h.old_len, h.new_start, h.new_len)?;
+
+        let (mut x, mut y) = (h.old_start, h.new_start);
+        for (n, l) in h.lines.iter().enumerate() {
+            let (kind, line, last) = match *l {
+                Line::Context(v) => {
+                    x += 1;
+                    y += 1;
+                    ("context", v, x > file[0].lines.len() && file[0].missing_eol)
+                },
+                Line::Remove(v) => {
+                    x += 1;
+                    ("delete", v, x > file[0].lines.len() && file[0].missing_eol)
+                },
+                Line::Add(v) => {
+                    y += 1;
+                    ("insert", v, y > file[1].lines.len() && file[1].missing_eol)
+                },
+            };
+            if n > 0 {
+                write!(out, ",")?;
+            }
+            write!(out, "{{\"type\":\"{}\",\"text\":{}", kind, json_string(line))?;
+            if last {
+                write!(out, ",\"no_newline\":true")?;
+            }
+            write!(out, "}}")?;
+        }
+        write!(out, "]}}")?;
+    }
+    writeln!(out, "]}}")?;
 
-    /// File to be compared
-    #[clap()]
-    file2: PathBuf
+    Ok(())
 }
 
-enum Status {
-    SAME,
-    DIFFER
+/// Print both files merged into one, each line in the format for where it
+/// came from. Lines in both files are numbered as in the first.
+#[allow(non_snake_case)]
+fn print_lines(
+    out: &mut impl Write,
+    TT: &Globals,
+    file: &[Contents; 2],
+    lines: &[Vec<&[u8]>; 2],
+    d: &[Diff],
+) -> Result<()> {
+    let [old, new, same] = &TT.line_format;
+    let eol = |i: usize, n: usize| n < lines[i].len() || !file[i].missing_eol;
+    let mut x = 1;
+
+    for t in d {
+        for n in x..t.a {
+            same.write(out, lines[0][n - 1], n, eol(0, n))?;
+        }
+        for n in t.a..=t.b {
+            old.write(out, lines[0][n - 1], n, eol(0, n))?;
+        }
+        for n in t.c..=t.d {
+            new.write(out, lines[1][n - 1], n, eol(1, n))?;
+        }
+        x = t.b + 1;
+    }
+    for n in x..=lines[0].len() {
+        same.write(out, lines[0][n - 1], n, eol(0, n))?;
+    }
+
+    Ok(())
 }
 
-impl Default for Status {
-    fn default() -> Status{
-        return Status::SAME;
+/// Format one side of a context hunk header, as its first and last lines.
+/// An empty range is reported as the line before it, and a single line
+/// only once.
+fn context_range(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{}", start - 1),
+        1 => format!("{}", start),
+        n => format!("{},{}", start, start + n - 1),
     }
 }
 
-///
-#[derive(Default)]
-struct Globals {
-    ///
-    exitval: i32,
+/// Print the changes as a context diff: each hunk shows its old lines and
+/// then its new ones, marking lines only on one side with - or +, and
+/// lines replaced by others with !. A side with nothing but context is
+/// left out.
+#[allow(non_snake_case)]
+fn print_context(
+    out: &mut impl Write,
+    TT: &Globals,
+    names: &[PathBuf],
+    file: &[Contents; 2],
+    lines: &[Vec<&[u8]>; 2],
+    d: &[Diff],
+) -> Result<()> {
+    for (i, prefix) in [b"*** ", b"--- "].iter().enumerate() {
+        let label = match TT.optflags.label.get(i) {
+            Some(v) => v.clone(),
+            None => show_label(&names[i], &TT.st[i], TT.format, !TT.optflags.no_quoting)?,
+        };
+        paint(out, TT.color(|p| &p.file), &[*prefix, label.as_bytes()])?;
+    }
 
-    ///
-    ct: i64,
+    let mut function = FunctionFinder::new(&lines[0], &TT.function);
+
+    for h in &show_hunks(TT, lines, d) {
+        paint(out, TT.color(|p| &p.hdr), &[b"***************", &function.header(h.old_start)])?;
+
+        // Each change is a run of removed lines then added ones. If it has
+        // both, they're all marked with !.
+        let mut marks = Vec::with_capacity(h.lines.len());
+        let mut i = 0;
+        while i < h.lines.len() {
+            let run = h.lines[i..].iter().take_while(|l| !matches!(l, Line::Context(_))).count();
+            if run == 0 {
+                marks.push(b' ');
+                i += 1;
+                continue;
+            }
+            let changed = &h.lines[i..i + run];
+            let both = matches!(changed.first(), Some(Line::Remove(_))) && matches!(changed.last(), Some(Line::Add(_)));
+            marks.extend(changed.iter().map(|l| match (both, l) {
+                (true, _) => b'!',
+                (false, Line::Remove(_)) => b'-',
+                (false, _) => b'+',
+            }));
+            i += run;
+        }
 
-    ///
-    start: String,
+        let sides = [
+            (b"*** ", h.old_start, h.old_len, b" ****", TT.color(|p| &p.del)),
+            (b"--- ", h.new_start, h.new_len, b" ----", TT.color(|p| &p.ins)),
+        ];
+        for (side, (prefix, start, len, suffix, code)) in sides.iter().enumerate() {
+            let range = context_range(*start, *len);
+            paint(out, TT.color(|p| &p.hdr), &[*prefix, range.as_bytes(), *suffix])?;
+
+            // The lines on this side: context, and removed or added ones.
+            let shown = h.lines.iter().zip(&marks).filter(|(l, _)| match l {
+                Line::Context(_) => true,
+                Line::Remove(_) => side == 0,
+                Line::Add(_) => side == 1,
+            });
+            if shown.clone().all(|(_, m)| *m == b' ') {
+                continue;
+            }
 
-    ///
-    optflags: Args,
+            for (n, (l, marker)) in shown.enumerate() {
+                let line = match *l {
+                    Line::Context(v) | Line::Remove(v) | Line::Add(v) => v,
+                };
+                let last = start + n == file[side].lines.len() && file[side].missing_eol;
+                let code = match marker {
+                    b' ' => None,
+                    _ => *code,
+                };
+                print_line(out, TT, *marker, code, line, &[], last)?;
+            }
+        }
+    }
 
-    ///
-    dir_num: i64,
+    Ok(())
+}
 
-    ///
-    size: i64,
+/// Pad `out` from column `from` to column `to`, with tabs every `tabsize`
+/// where they fit unless -t asked for spaces. Returns the column it got to.
+fn tab_from_to(out: &mut Vec<u8>, from: usize, to: usize, expand_tabs: bool, tabsize: usize) -> usize {
+    let mut from = from;
+
+    if !expand_tabs {
+        let mut tab = from + tabsize - from % tabsize;
+        while tab <= to {
+            out.push(b'\t');
+            from = tab;
+            tab += tabsize;
+        }
+    }
+    out.resize(out.len() + to.saturating_sub(from), b' ');
 
-    ///
-    is_binary: bool,
+    to
+}
 
-    ///
-    status: Status,
+/// Add as much of `line` to `out` as fits in `bound` columns, for a column
+/// of side by side output starting at column `indent`. Returns the column
+/// the text reached, counted from the start of the column like its tabs.
+fn print_half_line(out: &mut Vec<u8>, line: &[u8], indent: usize, bound: usize, expand_tabs: bool, tabsize: usize) -> usize {
+    let mut in_pos = 0;
+    let mut out_pos = 0;
+
+    for &c in line {
+        match c {
+            b'\t' => {
+                let spaces = tabsize - in_pos % tabsize;
+
+                // A tab only goes out if everything before it did.
+                if in_pos == out_pos {
+                    let tabstop = out_pos + spaces;
+                    if expand_tabs {
+                        let tabstop = tabstop.min(bound);
+                        out.resize(out.len() + tabstop.saturating_sub(out_pos), b' ');
+                        out_pos = out_pos.max(tabstop);
+                    } else if tabstop < bound {
+                        out.push(c);
+                        out_pos = tabstop;
+                    }
+                }
+                in_pos += spaces;
+            },
+            b'\r' => {
+                out.push(c);
+                tab_from_to(out, 0, indent, expand_tabs, tabsize);
+                in_pos = 0;
+                out_pos = 0;
+            },
+            // UTF-8 continuation bytes go with the character they continue.
+            0x80..=0xbf => {
+                if in_pos == out_pos && in_pos <= bound {
+                    out.push(c);
+                }
+            },
+            // Other control characters take no room.
+            0..=0x1f | 0x7f => {
+                if in_pos < bound {
+                    out.push(c);
+                }
+            },
+            _ => {
+                in_pos += 1;
+                if in_pos <= bound {
+                    out.push(c);
+                    out_pos = in_pos;
+                }
+            },
+        }
+    }
 
-    ///
-    change: i64,
+    out_pos
+}
 
-    /// Length of the root paths for each dir entry.
-    len: [PathBuf; 2],
+/// Print the files in two columns, lines that are the same side by side
+/// and changes paired up as far as they go. The gutter between the columns
+/// marks lines that differ with |, and lines only in the first or second
+/// file with < or >. -W sets the total width.
+#[allow(non_snake_case)]
+fn print_side_by_side(
+    out: &mut impl Write,
+    TT: &Globals,
+    file: &[Contents; 2],
+    lines: &[Vec<&[u8]>; 2],
+    d: &[Diff],
+) -> Result<()> {
+    let expand_tabs = TT.optflags.expand_tabs;
+    let tabsize = TT.optflags.tabsize;
+    let width = TT.optflags.width.unwrap_or(130);
+
+    // Start the second column on a tab stop near the middle, leaving a
+    // gutter of at least 3.
+    let t = if expand_tabs { 1 } else { tabsize };
+    let off = (width + t + 3) / (2 * t) * t;
+    let half = off.saturating_sub(3).min(width.saturating_sub(off));
+    let col2 = if half > 0 { off } else { width };
+
+    // Whether line i of a side ends in a newline.
+    let eol = |side: usize, i: usize| i + 1 < file[side].lines.len() || !file[side].missing_eol;
+
+    let mut row = |left: Option<usize>, sep: u8, right: Option<usize>| -> Result<()> {
+        let mut buf = vec![];
+        let mut col = 0;
+        let mut newline = false;
+
+        if let Some(i) = left {
+            newline |= eol(0, i);
+            col = print_half_line(&mut buf, lines[0][i], 0, half, expand_tabs, tabsize);
+        }
 
-    ///
-    offset: [i64; 2],
+        if sep != b' ' {
+            col = tab_from_to(&mut buf, col, (half + col2 - 1) / 2, expand_tabs, tabsize) + 1;
+            let sep = match (sep, right) {
+                (b'|', Some(j)) if newline != eol(1, j) => match newline {
+                    true => b'/',
+                    false => b'\\',
+                },
+                _ => sep,
+            };
+            buf.push(sep);
+        }
 
-    ///
-    st: [Metadata; 2],
+        if let Some(j) = right {
+            newline |= eol(1, j);
+            if !lines[1][j].is_empty() {
+                col = tab_from_to(&mut buf, col, col2, expand_tabs, tabsize);
+                print_half_line(&mut buf, lines[1][j], col, half, expand_tabs, tabsize);
+            }
+        }
 
-    /// List of directories and files under the specified paths.
-    dir: [Vec<walkdir::DirEntry>; 2]
-}
+        let code = match sep {
+            b'<' => TT.color(|p| &p.del),
+            b'>' => TT.color(|p| &p.ins),
+            _ => None,
+        };
+        match newline {
+            true => paint(out, code, &[&buf])?,
+            false => out.write_all(&buf)?,
+        }
 
-#[derive(Default)]
-struct Metadata {
-    metadata: Option<fs::Metadata>
-}
+        Ok(())
+    };
 
-#[derive(Default)]
-struct Diff {
-    a: i64,
-    b: i64,
-    c: i64,
-    d: i64,
-    prev: i64, 
-    suff: i64
+    let (mut x, mut y) = (0, 0);
+
+    // Each change follows the lines the files have in common before it.
+    let end = Diff { a: lines[0].len() + 1, c: lines[1].len() + 1, ..Default::default() };
+    for t in d.iter().chain([&end]) {
+        while x + 1 < t.a {
+            if !TT.optflags.suppress_common_lines {
+                row(Some(x), b' ', Some(y))?;
+            }
+            x += 1;
+            y += 1;
+        }
+        while x < t.b || y < t.d {
+            let left = Some(x).filter(|_| x < t.b);
+            let right = Some(y).filter(|_| y < t.d);
+            let sep = match (left, right) {
+                (Some(_), Some(_)) => b'|',
+                (Some(_), None) => b'<',
+                _ => b'>',
+            };
+            row(left, sep, right)?;
+            x += left.is_some() as usize;
+            y += right.is_some() as usize;
+        }
+    }
+
+    Ok(())
 }
 
-impl Metadata {
-    fn is_dir(&self) -> bool {
-        match &self.metadata {
-            Some(v) => return v.is_dir(),
-            None => return false
+/// Print both files merged into one, with the lines only in the first
+/// under #ifndef NAME and those only in the second under #ifdef NAME, so
+/// the preprocessor can pick either.
+#[allow(non_snake_case)]
+fn print_ifdef(
+    out: &mut impl Write,
+    TT: &Globals,
+    file: &[Contents; 2],
+    lines: &[Vec<&[u8]>; 2],
+    d: &[Diff],
+) -> Result<()> {
+    let name = TT.optflags.ifdef.as_deref().unwrap_or_default();
+    let mut x = 0;
+
+    for t in d {
+        for line in &lines[0][x..t.a - 1] {
+            out.write_all(line)?;
+            out.write_all(b"\n")?;
+        }
+        x = t.b;
+
+        let old = &lines[0][t.a - 1..t.b];
+        let new = &lines[1][t.c - 1..t.d];
+        match (old.is_empty(), new.is_empty()) {
+            (false, true) => writeln!(out, "#ifndef {}", name)?,
+            (true, false) => writeln!(out, "#ifdef {}", name)?,
+            _ => writeln!(out, "#ifndef {}", name)?,
+        }
+        for line in old {
+            out.write_all(line)?;
+            out.write_all(b"\n")?;
+        }
+        if !old.is_empty() && !new.is_empty() {
+            writeln!(out, "#else /* {} */", name)?;
+        }
+        for line in new {
+            out.write_all(line)?;
+            out.write_all(b"\n")?;
+        }
+        match new.is_empty() {
+            true => writeln!(out, "#endif /* ! {} */", name)?,
+            false => writeln!(out, "#endif /* {} */", name)?,
+        }
+    }
+
+    // The rest is the same, down to a missing newline at the end.
+    let rest = &lines[0][x..];
+    for (i, line) in rest.iter().enumerate() {
+        out.write_all(line)?;
+        if i + 1 < rest.len() || !file[0].missing_eol {
+            out.write_all(b"\n")?;
         }
     }
+
+    Ok(())
 }
 
-impl TryFrom<&PathBuf> for Metadata {
-    type Error = anyhow::Error;
-    fn try_from(p: &PathBuf) -> Result<Metadata, Self::Error> {
-        return Ok(Metadata{metadata: Some(fs::metadata(p)?)});
+/// Format a range of lines for an ed command, `a` or `a,b`.
+fn ed_range(a: usize, b: usize) -> String {
+    match a == b {
+        true => format!("{}", a),
+        false => format!("{},{}", a, b),
     }
 }
 
+/// Print --stat's summary: a line for each file with how many lines
+/// changed and a bar of +s and -s to scale, then the totals, like git.
+fn print_stat(out: &mut impl Write, stat: &[(PathBuf, Option<(usize, usize)>)]) -> Result<()> {
+    const BAR: usize = 50;
+
+    if stat.is_empty() {
+        return Ok(());
+    }
 
+    let names = stat.iter().map(|(name, _)| name.to_string_lossy()).collect::<Vec<_>>();
+    let namew = names.iter().map(|v| v.chars().count()).max().unwrap_or_default();
+    let most = stat.iter().filter_map(|(_, n)| n.map(|(a, r)| a + r)).max().unwrap_or_default();
+    let numw = most.to_string().len();
 
-fn is_a_tty(stderr: bool) -> bool {
-    let stream = if stderr {
-        atty::Stream::Stderr
-    } else {
-        atty::Stream::Stdout
+    // Scale the bars down to fit, but don't let a change vanish.
+    let scale = |n: usize| match most > BAR && n > 0 {
+        true => (n * BAR / most).max(1),
+        false => n,
     };
 
-    atty::is(stream)
-}
+    let (mut added, mut removed) = (0, 0);
+    for (name, n) in names.iter().zip(stat.iter().map(|(_, n)| n)) {
+        match n {
+            Some((a, r)) => {
+                writeln!(out, " {:<namew$} | {:>numw$} {}{}", name, a + r,
+                    "+".repeat(scale(*a)), "-".repeat(scale(*r)), namew = namew, numw = numw)?;
+                added += a;
+                removed += r;
+            },
+            None => writeln!(out, " {:<namew$} | Bin", name, namew = namew)?,
+        }
+    }
+
+    let plural = |n: usize| if n == 1 { "" } else { "s" };
+    write!(out, " {} file{} changed", stat.len(), plural(stat.len()))?;
+    if added > 0 {
+        write!(out, ", {} insertion{}(+)", added, plural(added))?;
+    }
+    if removed > 0 {
+        write!(out, ", {} deletion{}(-)", removed, plural(removed))?;
+    }
+    writeln!(out)?;
 
-fn is_stdin(p: &PathBuf) -> bool {
-    return p.to_string_lossy() == "-";
+    Ok(())
 }
 
-#[allow(non_snake_case)]
-fn do_diff(files: &Vec<PathBuf>, TT: &Globals) {
-    let mut i: i64 = 1;
-    let mut size: i64 = 1;
-    let mut x: i64 = 0;
-    let mut change: i64 = 0;
-    let mut ignore_white: i64 = 0;
-    let mut start1: i64 = 0;
-    let mut end1: i64 = 0;
-    let mut start2: i64 = 0;
-    let mut end2: i64 = 0;
-
-    let mut d: Diff = Default::default();
-
-    let llist: &Args = &TT.optflags;
-
-    TT.offset[0] = 0;
-    TT.offset[1] = 0;
-
-    let mut J = diff(files);
- 
-    if J != 0 {
-        return //No need to compare, have to status only
-    }
- 
-//    d = xzalloc(size *sizeof(struct diff));
-//    do {
-//      ignore_white = 0;
-//      for (d[x].a = i; d[x].a <= file[0].len; d[x].a++) {
-//        if (J[d[x].a] != (J[d[x].a - 1] + 1)) break;
-//        else continue;
-//      }
-//      d[x].c = (J[d[x].a - 1] + 1);
- 
-//      for (d[x].b = (d[x].a - 1); d[x].b <= file[0].len; d[x].b++) {
-//        if (J[d[x].b + 1]) break;
-//        else continue;
-//      }
-//      d[x].d = (J[d[x].b + 1] - 1);
- 
-//      if ((toys.optflags & FLAG_B)) {
-//        if (d[x].a <= d[x].b) {
-//          if ((TT.offset[0][d[x].b] - TT.offset[0][d[x].a - 1])
-//              == (d[x].b - d[x].a + 1))
-//            ignore_white = 1;
-//        } else if (d[x].c <= d[x].d){
-//          if ((TT.offset[1][d[x].d] - TT.offset[1][d[x].c - 1])
-//              == (d[x].d - d[x].c + 1))
-//            ignore_white = 1;
-//        }
-//      }
- 
-//      if ((d[x].a <= d[x].b || d[x].c <= d[x].d) && !ignore_white)
-//        change = 1; //is we have diff ?
- 
-//      if (!ignore_white) d = xrealloc(d, (x + 2) *sizeof(struct diff));
-//      i = d[x].b + 1;
-//      if (i > file[0].len) break;
-//      J[d[x].b] = d[x].d;
-//      if (!ignore_white) x++;
-//    } while (i <= file[0].len);
- 
-//    i = x+1;
-//    TT.status = change; //update status, may change bcoz of -w etc.
- 
-//    if (!(toys.optflags & FLAG_q) && change) {  //start of !FLAG_q
-//      if (toys.optflags & FLAG_color) printf("\e[1m");
-//      if (toys.optflags & FLAG_L) printf("--- %s\n", llist->arg);
-//      else show_label("---", files[0], &(TT).st[0]);
-//      if (((toys.optflags & FLAG_L) && !llist->next) || !(toys.optflags & FLAG_L))
-//        show_label("+++", files[1], &(TT).st[1]);
-//      else {
-//        while (llist->next) llist = llist->next;
-//        printf("+++ %s\n", llist->arg);
-//      }
-//      if (toys.optflags & FLAG_color) printf("\e[0m");
- 
-//      struct diff *t, *ptr1 = d, *ptr2 = d;
-//      while (i) {
-//        long a,b;
- 
-//        if (TT.ct > file[0].len) TT.ct = file[0].len; //trim context to file len.
-//        if (ptr1->b < ptr1->a && ptr1->d < ptr1->c) {
-//          i--;
-//          continue;
-//        }
-//        //Handle the context stuff
-//        a =  ptr1->a;
-//        b =  ptr1->b;
- 
-//        b  = MIN(file[0].len, b);
-//        if (i == x + 1) ptr1->suff = MAX(1,a - TT.ct);
-//        else {
-//          if ((ptr1 - 1)->prev >= (ptr1->a - TT.ct))
-//            ptr1->suff = (ptr1 - 1)->prev + 1;
-//          else ptr1->suff =  ptr1->a - TT.ct;
-//        }
-//  calc_ct:
-//        if (i > 1) {
-//          if ((ptr2->b + TT.ct) >= (ptr2  + 1)->a) {
-//            ptr2++;
-//            i--;
-//            goto calc_ct;
-//          } else ptr2->prev = ptr2->b + TT.ct;
-//        } else ptr2->prev = ptr2->b;
-//        start1 = (ptr2->prev - ptr1->suff + 1);
-//        end1 = (start1 == 1) ? -1 : start1;
-//        start2 = MAX(1, ptr1->c - (ptr1->a - ptr1->suff));
-//        end2 = ptr2->prev - ptr2->b + ptr2->d;
- 
-//        if (toys.optflags & FLAG_color) printf("\e[36m");
-//        printf("@@ -%ld", start1 ? ptr1->suff: (ptr1->suff -1));
-//        if (end1 != -1) printf(",%ld ", ptr2->prev-ptr1->suff + 1);
-//        else putchar(' ');
- 
-//        printf("+%ld", (end2 - start2 + 1) ? start2: (start2 -1));
-//        if ((end2 - start2 +1) != 1) printf(",%ld ", (end2 - start2 +1));
-//        else putchar(' ');
-//        printf("@@");
-//        if (toys.optflags & FLAG_color) printf("\e[0m");
-//        putchar('\n');
- 
-//        for (t = ptr1; t <= ptr2; t++) {
-//          if (t== ptr1) print_diff(t->suff, t->a-1, ' ', TT.offset[0], file[0].fp);
-//          print_diff(t->a, t->b, '-', TT.offset[0], file[0].fp);
-//          print_diff(t->c, t->d, '+', TT.offset[1], file[1].fp);
-//          if (t == ptr2)
-//            print_diff(t->b+1, (t)->prev, ' ', TT.offset[0], file[0].fp);
-//          else print_diff(t->b+1, (t+1)->a-1, ' ', TT.offset[0], file[0].fp);
-//        }
-//        ptr2++;
-//        ptr1 = ptr2;
-//        i--;
-//      } //end of while
-//    } //End of !FLAG_q
-//    free(d);
-//    free(J);
-//    free(TT.offset[0]);
-//    free(TT.offset[1]);
+/// Print the changes as an ed script that turns the first file into the
+/// second. The commands go from the bottom of the file up, so each one's
+/// line numbers are still right when ed gets to it.
+fn print_ed(out: &mut impl Write, new: &[&[u8]], d: &[Diff]) -> Result<()> {
+    for t in d.iter().rev().filter(|t| !t.ignore) {
+        match (t.a > t.b, t.c > t.d) {
+            (true, _) => writeln!(out, "{}a", t.a - 1)?,
+            (false, true) => {
+                writeln!(out, "{}d", ed_range(t.a, t.b))?;
+                continue;
+            },
+            (false, false) => writeln!(out, "{}c", ed_range(t.a, t.b))?,
+        }
+
+        // A line that's just "." would end the text, so it goes in as ".."
+        // and is fixed up with a substitution, after which adding resumes.
+        let text = &new[t.c - 1..t.d];
+        for (i, line) in text.iter().enumerate() {
+            if *line == b"." {
+                out.write_all(b"..\n.\ns/.//\n")?;
+                if i + 1 < text.len() {
+                    out.write_all(b"a\n")?;
+                }
+            } else {
+                out.write_all(line)?;
+                out.write_all(b"\n")?;
+            }
+        }
+        if text.last() != Some(&&b"."[..]) {
+            out.write_all(b".\n")?;
+        }
+    }
+
+    Ok(())
 }
 
 #[allow(non_snake_case)]
-fn show_status(files: &Vec<PathBuf>, TT: &Globals) {
+fn show_status(files: &[PathBuf], TT: &Globals) {
   match TT.status {
     Status::SAME => {
       if TT.optflags.report_identical_files {
         println!("Files {} and {} are identical", files[0].to_string_lossy(), files[1].to_string_lossy());
       }
     }
+    Status::TROUBLE => {}
     Status::DIFFER => {
-        if TT.optflags.brief || TT.is_binary {
-            println!("Files {} and {} differ", files[0].to_string_lossy(), files[1].to_string_lossy())
+        if TT.optflags.brief || (TT.is_binary && TT.format != Format::Stat && TT.format != Format::Json) {
+            print!("Files {} and {} differ", files[0].to_string_lossy(), files[1].to_string_lossy());
+
+            // With -q, say if it might only be the line endings, from what
+            // was read of the files to compare them.
+            match TT.optflags.brief {
+                true => match (TT.endings[0].most(), TT.endings[1].most()) {
+                    (Some(a), Some(b)) if a != b => println!(" (lines end in {} and {})", a, b),
+                    _ => println!(),
+                },
+                false => println!(),
+            }
         }
     }
   }
@@ -369,29 +1987,31 @@ fn concat_file_path(path: &Path, default_path: &Path) -> PathBuf {
     final_path
 }
 
-fn create_empty_entry(l: usize, r: usize, j: Ordering, TT: &Globals) -> Result<()> {
-    let mut st: Vec<fs::Metadata> = Default::default();
-    let mut f: Vec<PathBuf> = Default::default();
-    let mut path: Vec<PathBuf> = Default::default();
+#[allow(non_snake_case)]
+fn create_empty_entry(l: usize, r: usize, j: Ordering, TT: &mut Globals) -> Result<()> {
+    let mut st: [Metadata; 2] = Default::default();
+    let mut f: [PathBuf; 2] = Default::default();
+    let mut path: [PathBuf; 2] = Default::default();
+
+    // With -N the side a file is missing from reads as /dev/null, but is
+    // still named after where the file would be.
+    let absent = match j {
+        Ordering::Greater => Some(0),
+        Ordering::Less => Some(1),
+        Ordering::Equal => None,
+    };
 
-    if j == Ordering::Greater && TT.optflags.new_file {
-        path[0] = concat_file_path(&TT.len[0],
-            TT.dir[1][r]
-            .path()
-            .strip_prefix(&TT.len[1])?);
-        f[0] = Path::new("/dev/null").to_path_buf();
-        f[1] = TT.dir[1][r].path().to_path_buf();
-        path[1] = f[1].to_path_buf();
-        st.insert(0, fs::metadata(&f[1])?);
-        st.insert(1, st[0].clone());
-    }
-    else if j == Ordering::Less && TT.optflags.new_file {
-        path[1] = concat_file_path(&TT.len[0], TT.dir[0][l].path().strip_prefix(&TT.len[0])?);
-        f[1] = Path::new("/dev/null").to_path_buf();
-        f[0] = TT.dir[0][l].path().to_path_buf();
-        path[0] = f[0].to_path_buf();
-        st.insert(0, fs::metadata(&f[0])?);
-        st.insert(1, st[0].clone());
+    if let Some(a) = absent {
+        let e = match a {
+            0 => &TT.dir[1][r],
+            _ => &TT.dir[0][l],
+        };
+
+        path[a] = concat_file_path(&TT.len[a], e.path().strip_prefix(&TT.len[1 - a])?);
+        f[a] = devnull().to_path_buf();
+        f[1 - a] = e.path().to_path_buf();
+        path[1 - a] = f[1 - a].to_path_buf();
+        st[1 - a] = TT.stat(&f[1 - a])?;
     }
 
     if j == Ordering::Equal {
@@ -401,136 +2021,375 @@ fn create_empty_entry(l: usize, r: usize, j: Ordering, TT: &Globals) -> Result<(
                 false => TT.dir[i][r].path().to_path_buf()
             };
             path[i] = f[i].to_path_buf();
-            st[i] = fs::metadata(&f[i])?;
+            st[i] = TT.stat(&f[i])?;
         }
     }
 
-    if st[0].is_dir() && st[1].is_dir() {
-        println!("Common subdirectories: {:?} and {:?}", path[0], path[1]);
-    } else if !st[0].is_file() && !st[0].is_dir() {
-        println!("File {:?} is not a regular file or directory and was skipped", path[0]);
-    } else if !st[1].is_file() && !st[1].is_dir() {
-        println!("File {:?} is not a regular file or directory and was skipped", path[1]);
+    // The missing side has no metadata, and reads as an empty file.
+    let is_file = |i: usize| absent == Some(i) || st[i].is_file();
+    let kind = |i: usize| match absent == Some(i) {
+        true => "nonexistent file",
+        false => st[i].kind(),
+    };
+
+    // With --no-dereference, links are the same if they point to the same
+    // place. A missing side (with -N) has no metadata of its own.
+    let link = |i: usize| match absent == Some(i) {
+        true => None,
+        false => fs::read_link(&f[i]).ok(),
+    };
+
+    if st[0].is_symlink() || st[1].is_symlink() {
+        match (link(0), link(1)) {
+            (Some(a), Some(b)) if a == b => TT.status = Status::SAME,
+            (Some(_), Some(_)) => {
+                println!("Symbolic links {} and {} differ", path[0].display(), path[1].display());
+                TT.status = Status::DIFFER;
+            },
+            _ => {
+                println!("File {} is a {} while file {} is a {}",
+                    path[0].display(), kind(0), path[1].display(), kind(1));
+                TT.status = Status::DIFFER;
+            },
+        }
+    } else if let Some(a) = absent.filter(|&a| st[1 - a].is_dir()) {
+        // With -r its contents come up next in the walk, each of them new.
+        if !TT.optflags.recurse {
+            only_in(&f[1 - a], TT);
+        }
+    } else if st[0].is_dir() && st[1].is_dir() {
+        // With -r their contents come up next in the walk.
+        if !TT.optflags.recurse {
+            println!("Common subdirectories: {} and {}", path[0].display(), path[1].display());
+        }
+    } else if (0..2).any(|i| !is_file(i) && !st[i].is_dir()) {
+        // Devices, sockets and pipes aren't read, as on the command line.
+        println!("File {} is a {} while file {} is a {}",
+            path[0].display(), kind(0), path[1].display(), kind(1));
+        TT.status = Status::DIFFER;
     } else if st[0].is_dir() != st[1].is_dir() {
         if st[0].is_dir() {
-            println!("File {:?} is a {} while file {:?} is a {}", path[0], "directory", path[1], "regular file");
+            println!("File {} is a directory while file {} is a regular file", path[0].display(), path[1].display());
         } else {
-            println!("File {:?} is a {} while file {:?} is a {}", path[0], "regular file", path[1], "directory");
+            println!("File {} is a regular file while file {} is a directory", path[0].display(), path[1].display());
         }
+        TT.status = Status::DIFFER;
     } else {
-        do_diff(&f, &TT);
-        show_status(&path, &TT);
+        TT.st = st;
+        do_diff(&f, &path, TT)?;
+        show_status(&path, TT);
     }
 
     Ok(())
 }
 
+/// Order paths a component at a time, comparing names as bytes so the
+/// order doesn't depend on the locale. This is the order a depth first
+/// walk visits a directory in when each directory's entries are sorted by
+/// name, which lets diff_dir pair up the two walks. With
+/// --ignore-file-name-case, names that differ only in case are equal.
+fn name_cmp(a: &Path, b: &Path, fold: bool) -> Ordering {
+    match fold {
+        true => {
+            let lower = |p: &Path| p.iter().map(|c| c.to_string_lossy().to_lowercase()).collect::<Vec<_>>();
+            lower(a).cmp(&lower(b))
+        },
+        false => a.cmp(b),
+    }
+}
+
+/// Index of the first entry after `dir[i]` that isn't inside it.
+fn skip_subtree(dir: &[walkdir::DirEntry], i: usize) -> usize {
+    let root = dir[i].path();
+
+    i + 1 + dir[i + 1..].iter().take_while(|e| e.path().starts_with(root)).count()
+}
+
+/// Report a file or directory found on only one side of the walk.
+#[allow(non_snake_case)]
+fn only_in(p: &Path, TT: &mut Globals) {
+    let parent = p.parent().unwrap_or(&TT.len[0]);
+    let name = p.file_name().unwrap_or_default().to_string_lossy();
+
+    match TT.format {
+        Format::Json => println!("{{\"only_in\":{},\"name\":{}}}",
+            json_string(parent.to_string_lossy().as_bytes()),
+            json_string(name.as_bytes())),
+        _ => println!("Only in {}: {}", parent.display(), name),
+    }
+    TT.status = Status::DIFFER;
+}
+
+/// Compare two directories' entries, pairing them up as dir_steps does.
 #[allow(non_snake_case)]
 fn diff_dir(start: &[usize; 2], TT: &mut Globals) -> Result<()> {
+    // Where the walks go doesn't depend on what the comparisons find, so
+    // plan them first, for -j to know what's coming.
+    let steps = dir_steps(start, TT)?;
+
+    // -j compares a batch of files at once, then they're shown in order.
+    for batch in steps.chunks(TT.optflags.jobs * 4) {
+        let mut prepared = match TT.optflags.jobs > 1 && !TT.optflags.brief {
+            true => prepare(batch, TT),
+            false => vec![],
+        };
+
+        for (i, &(l, r, j)) in batch.iter().enumerate() {
+            TT.prepared = prepared.get_mut(i).and_then(Option::take);
+
+            if TT.optflags.new_file || j == Ordering::Equal {
+                create_empty_entry(l, r, j, TT)?; //create non empty dirs/files if -N.
+            } else {
+                let e = match j {
+                    Ordering::Less => &TT.dir[0][l],
+                    _ => &TT.dir[1][r],
+                };
+                let p = e.path().to_path_buf();
+                only_in(&p, TT);
+            }
+            TT.differed |= TT.status == Status::DIFFER;
+        }
+    }
+    TT.prepared = None;
+
+    Ok(())
+}
+
+/// Walk both directory listings in step, pairing up entries by their path
+/// relative to the directory being compared. Both listings are sorted the
+/// same way, so this is a merge: whichever side has the smaller relative
+/// path is only on that side. Each step is the entry on the left, the one
+/// on the right, and how they compare: Less if the left one is only on the
+/// left, and Greater if the right one is only on the right.
+#[allow(non_snake_case)]
+fn dir_steps(start: &[usize; 2], TT: &Globals) -> Result<Vec<(usize, usize, Ordering)>> {
+    let mut steps = vec![];
 
     // left side file start
     let mut l: usize = start[0];
     // right side file start
     let mut r: usize = start[1];
 
-    
-    while l < TT.dir[0].len() && r < TT.dir[1].len() {
-        let f0 = TT.dir[0][l].path().strip_prefix(&TT.len[0])?;
-        let f1 = TT.dir[1][l].path().strip_prefix(&TT.len[1])?;
-
-        let j = f0.partial_cmp(f1).context("Unable to order files")?;
-
-        if !TT.optflags.new_file {
-            match j {
-                Ordering::Greater => {
-                    println!("Only in {:?}: {:?}", TT.len[0], f0);
-                    r += 1;
-                },
-                _ => {
-                    println!("Only in {:?}: {:?}", TT.len[1], f1);
-                    l += 1;
-                }
+    while l < TT.dir[0].len() || r < TT.dir[1].len() {
+        // Once one side runs out, everything left is only on the other.
+        let j = if l == TT.dir[0].len() {
+            Ordering::Greater
+        } else if r == TT.dir[1].len() {
+            Ordering::Less
+        } else {
+            let f0 = TT.dir[0][l].path().strip_prefix(&TT.len[0])?;
+            let f1 = TT.dir[1][r].path().strip_prefix(&TT.len[1])?;
+
+            name_cmp(f0, f1, TT.optflags.ignore_file_name_case)
+        };
+        steps.push((l, r, j));
+
+        match j {
+            Ordering::Greater => {
+                // Don't report each file in a directory only on the right.
+                r = match TT.optflags.new_file {
+                    true => r + 1,
+                    false => skip_subtree(&TT.dir[1], r),
+                };
+            },
+            Ordering::Less => {
+                l = match TT.optflags.new_file {
+                    true => l + 1,
+                    false => skip_subtree(&TT.dir[0], l),
+                };
+            },
+            Ordering::Equal => {
+                // A directory against a file: there's nothing to pair its
+                // contents with.
+                let dirs = [TT.dir[0][l].file_type().is_dir(), TT.dir[1][r].file_type().is_dir()];
+
+                l = match dirs {
+                    [true, false] => skip_subtree(&TT.dir[0], l),
+                    _ => l + 1,
+                };
+                r = match dirs {
+                    [false, true] => skip_subtree(&TT.dir[1], r),
+                    _ => r + 1,
+                };
             }
+        }
+    }
 
-            TT.status = Status::DIFFER;
+    Ok(steps)
+}
+
+/// Compare the pairs of files a batch of steps of diff_dir will, on -j
+/// threads. Anything that goes wrong is left for do_diff to run into again
+/// and report in its turn.
+#[allow(non_snake_case)]
+fn prepare(steps: &[(usize, usize, Ordering)], TT: &Globals) -> Vec<Option<([PathBuf; 2], Compared)>> {
+    // The files create_empty_entry would hand do_diff to read, if they're
+    // both regular files (or missing, with -N). Once a side's listing has
+    // run out, its index is past the end, so only look at the sides the
+    // step has an entry on.
+    let files = |(l, r, j): (usize, usize, Ordering)| {
+        let is_file = |i: usize, n: usize| TT.dir[i][n].file_type().is_file();
+        let path = |i: usize, n: usize| TT.dir[i][n].path().to_path_buf();
+        match j {
+            Ordering::Equal if is_file(0, l) && is_file(1, r) => Some([path(0, l), path(1, r)]),
+            Ordering::Greater if TT.optflags.new_file && is_file(1, r) => {
+                Some([devnull().to_path_buf(), path(1, r)])
+            },
+            Ordering::Less if TT.optflags.new_file && is_file(0, l) => {
+                Some([path(0, l), devnull().to_path_buf()])
+            },
+            _ => None,
         }
-        else {
-            create_empty_entry(l, r, j, &TT)?; //create non empty dirs/files if -N.
+    };
 
-            match j {
-                Ordering::Greater => {
-                    r += 1;
-                },
-                Ordering::Less => {
-                    l += 1;
-                },
-                Ordering::Equal => {
-                    l += 1;
-                    r += 1;
+    let next = AtomicUsize::new(0);
+    let done = thread::scope(|s| {
+        let workers = (0..TT.optflags.jobs)
+            .map(|_| s.spawn(|| {
+                let mut done = vec![];
+                loop {
+                    let i = next.fetch_add(1, AtomicOrdering::Relaxed);
+                    let step = match steps.get(i) {
+                        Some(v) => *v,
+                        None => return done,
+                    };
+                    if let Some(v) = files(step) {
+                        if let Ok(c) = compare(&v, TT) {
+                            done.push((i, v, c));
+                        }
+                    }
                 }
-            }
-        }
-    }
+            }))
+            .collect::<Vec<_>>();
 
-  if l == TT.dir[0].len() {
-    while r < TT.dir[1].len() {
-        if TT.optflags.new_file {
-            println!("Only in {}: {}", TT.dir[1][0].path().to_string_lossy(), TT.dir[1][r].path().strip_prefix(&TT.len[1])?.to_string_lossy());
-            TT.status = Status::DIFFER;
-        } else {
-            create_empty_entry(l, r, Ordering::Greater, TT)?;
-        }
-        TT.dir[1].remove(r);
-        r += 1;
-    }
-  } else if r == TT.dir[1].len() {
-    while l < TT.dir[0].len() {
-        if TT.optflags.new_file {
-        println!("Only in {}: {}", TT.dir[1][0].path().to_string_lossy(), TT.dir[0][l].path().strip_prefix(&TT.len[0])?.to_string_lossy());
-        TT.status = Status::DIFFER;
-      } else {
-        create_empty_entry(l, r, Ordering::Less, TT)?;
-      }
-      TT.dir[0].remove(l);
-      l += 1;
+        workers.into_iter()
+            .flat_map(|w| w.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            .collect::<Vec<_>>()
+    });
+
+    let mut prepared = steps.iter().map(|_| None).collect::<Vec<_>>();
+    for (i, v, c) in done {
+        prepared[i] = Some((v, c));
     }
-  }
 
-    Ok(())
+    prepared
 }
 
 fn diff_main(flags: Args) -> Result<Status>{
     #[allow(non_snake_case)]
     let mut TT: Globals = Globals{ optflags: flags, ..Default::default()};
 
-    let mut start: [usize; 2] = [1, 1];
+    TT.opts = DiffOptions::from(&TT.optflags);
+    TT.format = Format::from(&TT.optflags);
+
+    if TT.optflags.width == Some(0) {
+        bail!("invalid width '0'")
+    }
+    if TT.optflags.tabsize == 0 {
+        bail!("invalid tabsize '0'")
+    }
+    if TT.optflags.jobs == 0 {
+        bail!("invalid jobs '0'")
+    }
+
+    TT.function = TT.optflags.show_function_line.clone();
+    if TT.optflags.show_c_function {
+        TT.function.push(Regex::new("^[[:alpha:]$_]")?);
+    }
+
+    TT.palette = TT.optflags.palette.clone().unwrap_or_default();
 
-    let mut files: Vec<PathBuf> = Default::default();
+    let line_format = [
+        &TT.optflags.old_line_format,
+        &TT.optflags.new_line_format,
+        &TT.optflags.unchanged_line_format,
+    ];
+    TT.line_format = line_format.map(|v| v.as_ref().or(TT.optflags.line_format.as_ref()).cloned().unwrap_or_default());
 
-    if TT.optflags.color && !is_a_tty(true) {
-        TT.optflags.color = false;
+    TT.exclude = TT.optflags.exclude.clone();
+    for path in &TT.optflags.exclude_from {
+        let text = fs::read_to_string(path).with_context(|| path.to_string_lossy().to_string())?;
+        for line in text.lines().filter(|l| !l.is_empty()) {
+            TT.exclude.push(Pattern::new(line)?);
+        }
     }
 
-    {
-        files.insert(0, TT.optflags.file1.clone());
+    // auto only colors a terminal, and NO_COLOR turns it off unless color
+    // was asked for outright. Once decided, it's always or never.
+    let no_color = env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    if TT.optflags.color == When::Auto {
+        TT.optflags.color = match is_a_tty(false) && !no_color {
+            true => When::Always,
+            false => When::Never,
+        };
+    }
+
+    // Pair up the files to compare.
+    let files = TT.optflags.files.clone();
+    let pairs: Vec<[PathBuf; 2]> = match (&TT.optflags.from_file, &TT.optflags.to_file) {
+        (Some(v), _) => files.into_iter().map(|f| [v.clone(), f]).collect(),
+        (_, Some(v)) => files.into_iter().map(|f| [f, v.clone()]).collect(),
+        _ => match &files[..] {
+            [a, b] => vec![[a.clone(), b.clone()]],
+            [a] => bail!("missing operand after '{}'", a.to_string_lossy()),
+            v => bail!("extra operand '{}'", v[2].to_string_lossy()),
+        },
+    };
+
+    // One pair that can't be compared doesn't stop the rest.
+    let mut status = Status::SAME;
+    for v in pairs {
+        TT.status = Status::SAME;
+        TT.differed = false;
+        TT.trouble = false;
+        if let Err(e) = diff_files(v.to_vec(), &mut TT) {
+            eprintln!("diff: {:#}", e);
+            TT.trouble = true;
+        }
+        if TT.trouble {
+            TT.status = Status::TROUBLE;
+        }
+        status = status.max(TT.status);
+    }
+
+    print_stat(&mut io::stdout().lock(), &TT.stat)?;
+
+    Ok(status)
+}
 
+/// Compare one pair of files or directories.
+#[allow(non_snake_case)]
+fn diff_files(mut files: Vec<PathBuf>, TT: &mut Globals) -> Result<()> {
+    let mut start: [usize; 2] = [1, 1];
+
+    {
         TT.st[0] = match is_stdin(&files[0]) {
             // XXX: How do I fstat stdin in Rust?
             true => Default::default(),
-            false => Metadata::try_from(&files[0])?
+            false => stat_operand(&files[0], TT.optflags.new_file)?
         }
     }
 
     {
-        files.insert(1, TT.optflags.file2.clone());
-
         TT.st[1] = match is_stdin(&files[1]) {
             // XXX: How do I fstat stdin in Rust?
             true => Default::default(),
-            false => Metadata::try_from(&files[1])?
+            false => stat_operand(&files[1], TT.optflags.new_file)?
         }
     }
 
+    if is_stdin(&files[0]) && is_stdin(&files[1]) {
+        bail!("can't compare stdin to itself")
+    }
+
+    // -N stands in for one missing file, not both.
+    let missing = |files: &[PathBuf], st: &[Metadata; 2], i: usize| {
+        st[i].metadata.is_none() && !is_stdin(&files[i])
+    };
+    if missing(&files, &TT.st, 0) && missing(&files, &TT.st, 1) {
+        stat_operand(&files[0], false)?;
+    }
+
     if is_stdin(&files[0]) || is_stdin(&files[1]) {
         if TT.st[0].is_dir() {
             bail!("can't compare stdin to directory")
@@ -540,95 +2399,125 @@ fn diff_main(flags: Args) -> Result<Status>{
         }
     }
 
-    /// physically same device
-    #[cfg(unix)]
-    {
-        if TT.st[0].ino() == TT.st[1].ino() {
-            TT.status = Status::SAME;
-            show_status(files, TT);
-            return Ok(TT.status);
-        }
-    }
-
-    #[cfg(windows)]
-    {
-        if fs::canonicalize(&files[0])? == fs::canonicalize(&files[1])? {
-            TT.status = Status::SAME;
-            show_status(&files, &TT);
-            return Ok(TT.status);
-        }
+    if same_file(&files, &TT.st) && !TT.format.shows_common() {
+        TT.status = Status::SAME;
+        show_status(&files, TT);
+        return Ok(());
     }
 
     if TT.st[0].is_dir() && TT.st[1].is_dir() {
-        // Here it attempts to list both directories recursively,
-        // following symlinks and sorting by name...?
-
-        TT.dir[0] = walkdir::WalkDir::new(&files[0])
-            .follow_links(true)
-            .sort_by_file_name()
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .collect::<Vec<_>>();
-        
-        TT.len[0] = TT.dir[0].first().context("no first directory path")?.path().to_path_buf();
-
-        TT.dir[1] = walkdir::WalkDir::new(&files[1])
-            .follow_links(true)
-            .sort_by_file_name()
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .collect::<Vec<_>>();
+        // List both directories (recursively with -r), following symlinks
+        // unless --no-dereference, and sorting by name as name_cmp does.
+        // Names only the case of which differs still go in byte order, so
+        // the listing is the same every time. The first entry is the
+        // directory itself. A link back up the tree would go round
+        // forever, so walkdir reports it instead of following it.
+        let depth = match TT.optflags.recurse {
+            true => usize::MAX,
+            false => 1,
+        };
+        let fold = TT.optflags.ignore_file_name_case;
+
+        for (i, file) in files.iter().enumerate() {
+            let mut trouble = false;
+
+            // Excluding a directory skips everything under it too.
+            let dir = walkdir::WalkDir::new(file)
+                .follow_links(!TT.optflags.no_dereference)
+                .max_depth(depth)
+                .sort_by(move |a, b| {
+                    let (a, b) = (Path::new(a.file_name()), Path::new(b.file_name()));
+                    name_cmp(a, b, fold).then_with(|| a.cmp(b))
+                })
+                .into_iter()
+                .filter_entry(|e| e.depth() == 0 || !TT.excluded(file, e))
+                .filter_map(|e| match e {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        match (e.loop_ancestor(), e.path()) {
+                            (Some(_), Some(v)) => eprintln!("diff: {}: recursive directory loop", v.display()),
+                            _ => eprintln!("diff: {}", e),
+                        }
+                        trouble = true;
+                        None
+                    },
+                })
+                .collect::<Vec<_>>();
+            TT.dir[i] = dir;
+            TT.trouble |= trouble;
+
+            TT.len[i] = TT.dir[i].first().context("no first directory path")?.path().to_path_buf();
+        }
 
-        TT.len[1] = TT.dir[0].first().context("no first directory path")?.path().to_path_buf();
+        // Start at the first entry (after the root itself) whose relative
+        // path sorts at or after v. The walk is depth first with siblings
+        // sorted by name, which is the order Path comparison gives. If v is
+        // past everything on a side, there's nothing left to compare there.
+        if let Some(v) = &TT.optflags.starting_file {
+            for (i, s) in start.iter_mut().enumerate() {
+                let root = &TT.len[i];
 
-        // need to check every pathname whose last bit matches v
-        match &TT.optflags.starting_file {
-            Some(v) => {
-                start[0] = TT.dir[0]
+                *s = 1 + TT.dir[i][1..]
                     .iter()
-                    .position(|i| i.file_name() >= v)
-                    .unwrap_or(0);
-                start[1] = TT.dir[1]
-                    .iter()
-                    .position(|i| i.file_name() >= v)
-                    .unwrap_or(0);
-            },
-            None => {}
+                    .take_while(|e| e.path().strip_prefix(root).is_ok_and(|p| name_cmp(p, v, fold) == Ordering::Less))
+                    .count();
+            }
         }
 
-        TT.dir_num = 2;
-        TT.size = 0;
-
-        diff_dir(&start, &mut TT)?;
+        diff_dir(&start, TT)?;
+        if TT.differed {
+            TT.status = Status::DIFFER;
+        }
     }
     else {
         if TT.st[0].is_dir() || TT.st[1].is_dir() {
+            // Compare the file with the one of the same name in the
+            // directory, like "diff file dir/file".
             let d = TT.st[0].is_dir() as usize;
+            let name = files[d]
+                .file_name()
+                .ok_or_else(|| anyhow!("can't compare {} to directory", files[d].to_string_lossy()))?;
 
-            files[1 - d] = files[1 - d].with_file_name(&files[d]);
+            files[1 - d] = files[1 - d].join(name);
+            TT.st[1 - d] = stat_operand(&files[1 - d], TT.optflags.new_file && !missing(&files, &TT.st, d))?;
+        }
 
-            TT.st[1 - d] = match fs::metadata(&files[1-d]) {
-                Ok(v) => Metadata {metadata: Some(v)},
-                Err(e) => {
-                    bail!(e)
-                }
+        // Devices, sockets and named pipes aren't read: a pipe nothing's
+        // writing to would wait forever, and some devices never end. They
+        // differ from anything, like a file from a directory. /dev/null
+        // reads as empty.
+        let special = |i: usize| TT.st[i].is_special() && !is_shell_pipe(&files[i]) && files[i] != devnull();
+        if special(0) || special(1) {
+            println!("File {} is a {} while file {} is a {}",
+                files[0].display(), TT.st[0].kind(), files[1].display(), TT.st[1].kind());
+            TT.status = Status::DIFFER;
+            return Ok(());
+        }
+
+        // A missing file is read as empty, under its own name.
+        let names = files.clone();
+        for (i, file) in files.iter_mut().enumerate() {
+            if missing(&names, &TT.st, i) {
+                *file = devnull().to_path_buf();
             }
         }
 
-        do_diff(&files, &TT);
-        show_status(&files, &TT);
+        do_diff(&files, &names, TT)?;
+        show_status(&names, TT);
     }
 
-    return Ok(TT.status);
+    Ok(())
 }
 
-fn main() -> Result<()> {
+/// Exit 0 if the files are the same, 1 if they differ, and 2 if there
+/// was trouble.
+fn main() {
     let optflags = Args::parse();
 
     match diff_main(optflags) {
         Ok(v) => process::exit(v as i32),
         Err(v) => {
-            anyhow!("diff: {}", v);
+            eprintln!("diff: {:#}", v);
             process::exit(2);
         }
     }
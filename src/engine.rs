@@ -0,0 +1,738 @@
+//! The line diff behind the diff binary: an edit script between two
+//! sequences of lines, grouped into hunks of changes with their context.
+
+use regex::bytes::Regex;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// How lines are compared and grouped into hunks.
+#[derive(Debug, Clone)]
+pub struct DiffOptions {
+    /// Ignore case differences (-i).
+    pub ignore_case: bool,
+
+    /// Ignore changes in the amount of whitespace (-b).
+    pub ignore_space_change: bool,
+
+    /// Ignore all whitespace (-w).
+    pub ignore_all_space: bool,
+
+    /// Ignore changes whose lines are all blank (-B).
+    pub ignore_blank_lines: bool,
+
+    /// Ignore changes whose lines all match one of these (-I).
+    pub ignore_matching_lines: Vec<Regex>,
+
+    /// Find the smallest possible set of changes, however long it takes (-d).
+    pub minimal: bool,
+
+    /// Settle for a bigger set of changes sooner, when long runs of lines
+    /// match (--speed-large-files).
+    pub speed_large_files: bool,
+
+    /// Lines of context around each change (-U).
+    pub context: usize,
+
+    /// Leave out context at the ends of a hunk that -B or -I would ignore
+    /// (--minimal-context).
+    pub minimal_context: bool,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        DiffOptions {
+            ignore_case: false,
+            ignore_space_change: false,
+            ignore_all_space: false,
+            ignore_blank_lines: false,
+            ignore_matching_lines: vec![],
+            minimal: false,
+            speed_large_files: false,
+            context: 3,
+            minimal_context: false,
+        }
+    }
+}
+
+/// Build up options a call at a time from the defaults, as in
+/// `DiffOptions::new().ignore_case(true).context(1)`.
+impl DiffOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ignore case differences.
+    pub fn ignore_case(mut self, ignore_case: bool) -> Self {
+        self.ignore_case = ignore_case;
+        self
+    }
+
+    /// Ignore changes in the amount of whitespace.
+    pub fn ignore_space_change(mut self, ignore_space_change: bool) -> Self {
+        self.ignore_space_change = ignore_space_change;
+        self
+    }
+
+    /// Ignore all whitespace.
+    pub fn ignore_all_space(mut self, ignore_all_space: bool) -> Self {
+        self.ignore_all_space = ignore_all_space;
+        self
+    }
+
+    /// Ignore changes whose lines are all blank.
+    pub fn ignore_blank_lines(mut self, ignore_blank_lines: bool) -> Self {
+        self.ignore_blank_lines = ignore_blank_lines;
+        self
+    }
+
+    /// Ignore changes whose lines all match `re`; may be given more than once.
+    pub fn ignore_matching_lines(mut self, re: Regex) -> Self {
+        self.ignore_matching_lines.push(re);
+        self
+    }
+
+    /// Find the smallest possible set of changes.
+    pub fn minimal(mut self, minimal: bool) -> Self {
+        self.minimal = minimal;
+        self
+    }
+
+    /// Settle sooner when long runs of lines match.
+    pub fn speed_large_files(mut self, speed_large_files: bool) -> Self {
+        self.speed_large_files = speed_large_files;
+        self
+    }
+
+    /// Lines of context around each change.
+    pub fn context(mut self, context: usize) -> Self {
+        self.context = context;
+        self
+    }
+
+    /// Leave ignorable lines out of the context at the ends of a hunk.
+    pub fn minimal_context(mut self, minimal_context: bool) -> Self {
+        self.minimal_context = minimal_context;
+        self
+    }
+}
+
+/// A change between the two sequences: lines `a..=b` of the first are
+/// replaced by lines `c..=d` of the second, 1-based. A pure insertion has
+/// `b == a - 1`, a pure deletion `d == c - 1`.
+#[derive(Default)]
+pub struct Diff {
+    pub a: usize,
+    pub b: usize,
+    pub c: usize,
+    pub d: usize,
+
+    /// Set when the change doesn't count as a difference, as with -B or -I.
+    pub ignore: bool,
+}
+
+/// How many matches in a row make a run long enough for the heuristic.
+const SNAKE_LIMIT: isize = 20;
+
+/// Find the shortest edit script between two sequences.
+///
+/// This is Myers' O(ND) algorithm in its linear space, divide and conquer
+/// form: find the middle snake of the edit graph, then recurse on both
+/// halves. Elements that have to be deleted from `a` or inserted from `b`
+/// are flagged in `changed[0]` and `changed[1]` respectively.
+///
+/// Finding the true middle snake costs O(ND), which gets quadratic when two
+/// large files have little in common. Unless `minimal` is set, the search
+/// gives up after `too_expensive` edit steps and splits at the diagonal
+/// that got furthest instead: the result is still a correct edit script,
+/// just not necessarily the shortest one. With `heuristic`, it also gives
+/// up sooner, once a diagonal has come a long way with a long run of
+/// matches at the end of it.
+struct Myers<'a, T> {
+    a: &'a [T],
+    b: &'a [T],
+
+    /// Never take the too_expensive shortcut (-d).
+    minimal: bool,
+    too_expensive: isize,
+
+    /// Take the shortcut for long matching runs (--speed-large-files).
+    heuristic: bool,
+
+    /// Furthest reaching x for each diagonal, forward and backward.
+    fd: Vec<isize>,
+    bd: Vec<isize>,
+
+    /// Offset added to a diagonal number to index `fd`/`bd`.
+    off: isize,
+
+    changed: [Vec<bool>; 2],
+}
+
+impl<'a, T: PartialEq> Myers<'a, T> {
+    fn new(a: &'a [T], b: &'a [T], minimal: bool, heuristic: bool) -> Self {
+        let diags = a.len() + b.len() + 3;
+
+        // Roughly the square root of the number of diagonals, but at least 4096.
+        let mut too_expensive: isize = 1;
+        let mut n = diags;
+        while n != 0 {
+            too_expensive <<= 1;
+            n >>= 2;
+        }
+
+        Myers {
+            a,
+            b,
+            minimal,
+            too_expensive: too_expensive.max(4096),
+            heuristic,
+            fd: vec![0; diags],
+            bd: vec![0; diags],
+            off: b.len() as isize + 1,
+            changed: [vec![false; a.len()], vec![false; b.len()]],
+        }
+    }
+
+    fn eq(&self, x: isize, y: isize) -> bool {
+        self.a[x as usize] == self.b[y as usize]
+    }
+
+    /// Find the midpoint of the shortest edit script for a[xoff..xlim] versus
+    /// b[yoff..ylim], by running the search from both ends until they meet.
+    fn diag(&mut self, xoff: isize, xlim: isize, yoff: isize, ylim: isize) -> (isize, isize) {
+        let o = self.off;
+        let dmin = xoff - ylim;
+        let dmax = xlim - yoff;
+        let fmid = xoff - yoff;
+        let bmid = xlim - ylim;
+        let (mut fmin, mut fmax) = (fmid, fmid);
+        let (mut bmin, mut bmax) = (bmid, bmid);
+        // True if southeast corner is on an odd diagonal relative to the northwest.
+        let odd = (fmid - bmid) & 1 != 0;
+
+        self.fd[(fmid + o) as usize] = xoff;
+        self.bd[(bmid + o) as usize] = xlim;
+
+        let mut c = 0;
+        loop {
+            c += 1;
+            let mut big_snake = false;
+
+            // Extend the top-down search by an edit step in each diagonal.
+            if fmin > dmin {
+                fmin -= 1;
+                self.fd[(fmin - 1 + o) as usize] = -1;
+            } else {
+                fmin += 1;
+            }
+            if fmax < dmax {
+                fmax += 1;
+                self.fd[(fmax + 1 + o) as usize] = -1;
+            } else {
+                fmax -= 1;
+            }
+
+            let mut d = fmax;
+            while d >= fmin {
+                let tlo = self.fd[(d - 1 + o) as usize];
+                let thi = self.fd[(d + 1 + o) as usize];
+                let mut x = if tlo >= thi { tlo + 1 } else { thi };
+                let mut y = x - d;
+                let x0 = x;
+
+                while x < xlim && y < ylim && self.eq(x, y) {
+                    x += 1;
+                    y += 1;
+                }
+                big_snake |= x - x0 > SNAKE_LIMIT;
+                self.fd[(d + o) as usize] = x;
+                if odd && bmin <= d && d <= bmax && self.bd[(d + o) as usize] <= x {
+                    return (x, y);
+                }
+                d -= 2;
+            }
+
+            // Similarly extend the bottom-up search.
+            if bmin > dmin {
+                bmin -= 1;
+                self.bd[(bmin - 1 + o) as usize] = isize::MAX;
+            } else {
+                bmin += 1;
+            }
+            if bmax < dmax {
+                bmax += 1;
+                self.bd[(bmax + 1 + o) as usize] = isize::MAX;
+            } else {
+                bmax -= 1;
+            }
+
+            let mut d = bmax;
+            while d >= bmin {
+                let tlo = self.bd[(d - 1 + o) as usize];
+                let thi = self.bd[(d + 1 + o) as usize];
+                let mut x = if tlo < thi { tlo } else { thi - 1 };
+                let mut y = x - d;
+                let x0 = x;
+
+                while x > xoff && y > yoff && self.eq(x - 1, y - 1) {
+                    x -= 1;
+                    y -= 1;
+                }
+                big_snake |= x0 - x > SNAKE_LIMIT;
+                self.bd[(d + o) as usize] = x;
+                if !odd && fmin <= d && d <= fmax && x <= self.fd[(d + o) as usize] {
+                    return (x, y);
+                }
+                d -= 2;
+            }
+
+            if self.minimal {
+                continue;
+            }
+
+            // With the heuristic, a diagonal that's got well ahead of how
+            // many steps it took, and ends in a long run of matches, will
+            // do as a place to split.
+            if self.heuristic && big_snake && c > 200 {
+                if let Some(v) = self.good_diagonal(c, fmin, fmax, fmid, xoff, xlim, yoff, ylim, true) {
+                    return v;
+                }
+                if let Some(v) = self.good_diagonal(c, bmin, bmax, bmid, xoff, xlim, yoff, ylim, false) {
+                    return v;
+                }
+            }
+
+            if c < self.too_expensive {
+                continue;
+            }
+
+            // Too expensive: pick the forward or backward diagonal that made
+            // the most progress towards its corner and split there.
+            let mut fxybest = -1;
+            let mut fxbest = 0;
+            let mut d = fmin;
+            while d <= fmax {
+                let mut x = self.fd[(d + o) as usize].min(xlim);
+                let mut y = x - d;
+                if ylim < y {
+                    x = ylim + d;
+                    y = ylim;
+                }
+                if fxybest < x + y {
+                    fxybest = x + y;
+                    fxbest = x;
+                }
+                d += 2;
+            }
+
+            let mut bxybest = isize::MAX;
+            let mut bxbest = 0;
+            let mut d = bmin;
+            while d <= bmax {
+                let mut x = self.bd[(d + o) as usize].max(xoff);
+                let mut y = x - d;
+                if y < yoff {
+                    x = yoff + d;
+                    y = yoff;
+                }
+                if x + y < bxybest {
+                    bxybest = x + y;
+                    bxbest = x;
+                }
+                d += 2;
+            }
+
+            if (xlim + ylim) - bxybest < fxybest - (xoff + yoff) {
+                return (fxbest, fxybest - fxbest);
+            } else {
+                return (bxbest, bxybest - bxbest);
+            }
+        }
+    }
+
+    /// For the heuristic: the diagonal from dmin to dmax of the forward
+    /// search (or the backward one) that's got furthest for the `c` steps it
+    /// took and how far it strays from `mid`, if one's far enough along
+    /// and reached by at least SNAKE_LIMIT matches in a row.
+    #[allow(clippy::too_many_arguments)]
+    fn good_diagonal(
+        &self,
+        c: isize,
+        dmin: isize,
+        dmax: isize,
+        mid: isize,
+        xoff: isize,
+        xlim: isize,
+        yoff: isize,
+        ylim: isize,
+        forward: bool,
+    ) -> Option<(isize, isize)> {
+        let mut best = 0;
+        let mut found = None;
+
+        let mut d = dmax;
+        while d >= dmin {
+            let dd = d - mid;
+            let (x, v) = match forward {
+                true => {
+                    let x = self.fd[(d + self.off) as usize];
+                    (x, (x - xoff) + (x - d - yoff) - dd)
+                },
+                false => {
+                    let x = self.bd[(d + self.off) as usize];
+                    (x, (xlim - x) + (ylim - x + d) + dd)
+                },
+            };
+            let y = x - d;
+            let inside = match forward {
+                true => xoff + SNAKE_LIMIT <= x && x < xlim && yoff + SNAKE_LIMIT <= y && y < ylim,
+                false => xoff < x && x <= xlim - SNAKE_LIMIT && yoff < y && y <= ylim - SNAKE_LIMIT,
+            };
+            let snake = |k: isize| match forward {
+                true => self.eq(x - k, y - k),
+                false => self.eq(x + k - 1, y + k - 1),
+            };
+
+            if v > 12 * (c + dd.abs()) && v > best && inside && (1..=SNAKE_LIMIT).all(snake) {
+                best = v;
+                found = Some((x, y));
+            }
+            d -= 2;
+        }
+
+        found
+    }
+
+    /// Compare a[xoff..xlim] with b[yoff..ylim], flagging the changed elements.
+    fn compareseq(&mut self, mut xoff: isize, mut xlim: isize, mut yoff: isize, mut ylim: isize) {
+        // Slide down the bottom initial diagonal, and up the top one.
+        while xoff < xlim && yoff < ylim && self.eq(xoff, yoff) {
+            xoff += 1;
+            yoff += 1;
+        }
+        while xlim > xoff && ylim > yoff && self.eq(xlim - 1, ylim - 1) {
+            xlim -= 1;
+            ylim -= 1;
+        }
+
+        if xoff == xlim {
+            for y in yoff..ylim {
+                self.changed[1][y as usize] = true;
+            }
+        } else if yoff == ylim {
+            for x in xoff..xlim {
+                self.changed[0][x as usize] = true;
+            }
+        } else {
+            let (x, y) = self.diag(xoff, xlim, yoff, ylim);
+
+            self.compareseq(xoff, x, yoff, y);
+            self.compareseq(x, xlim, y, ylim);
+        }
+    }
+}
+
+/// Number each distinct element of `a` and `b`, equal ones alike, so the
+/// search compares integers instead of lines. Element i of a side has id
+/// i in its list, so changes found in the ids are changes in the lines.
+fn intern<T: Hash + Eq>(a: &[T], b: &[T]) -> [Vec<usize>; 2] {
+    let mut ids = HashMap::with_capacity(a.len() + b.len());
+
+    [a, b].map(|v| {
+        v.iter()
+            .map(|l| {
+                let n = ids.len();
+                *ids.entry(l).or_insert(n)
+            })
+            .collect()
+    })
+}
+
+/// Compute the list of changes turning `a` into `b`, trying as hard as
+/// `opts.minimal` and `opts.speed_large_files` say.
+pub fn diff<T: Hash + Eq>(a: &[T], b: &[T], opts: &DiffOptions) -> Vec<Diff> {
+    // The lines the files start and end with in common are left out, so
+    // only what's between them has to be hashed.
+    let head = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    let tail = a[head..].iter().rev().zip(b[head..].iter().rev()).take_while(|(x, y)| x == y).count();
+    let [a, b] = intern(&a[head..a.len() - tail], &b[head..b.len() - tail]);
+
+    let mut m = Myers::new(&a, &b, opts.minimal, opts.speed_large_files);
+    m.compareseq(0, a.len() as isize, 0, b.len() as isize);
+
+    // Unchanged lines pair up one to one, so walk both sides in lockstep
+    // and turn each run of changed lines into a Diff.
+    let changed = &m.changed;
+    let mut d: Vec<Diff> = vec![];
+    let (mut i, mut j) = (0, 0);
+
+    while i < a.len() || j < b.len() {
+        if (i < a.len() && changed[0][i]) || (j < b.len() && changed[1][j]) {
+            let (start1, start2) = (i, j);
+
+            while i < a.len() && changed[0][i] {
+                i += 1;
+            }
+            while j < b.len() && changed[1][j] {
+                j += 1;
+            }
+
+            d.push(Diff {
+                a: head + start1 + 1,
+                b: head + i,
+                c: head + start2 + 1,
+                d: head + j,
+                ..Default::default()
+            });
+        } else {
+            i += 1;
+            j += 1;
+        }
+    }
+
+    d
+}
+
+/// Reduce a line to the form it is compared in, as selected by `opts`.
+/// Output always shows the original line.
+pub fn canon<'a>(line: &'a [u8], opts: &DiffOptions) -> Cow<'a, [u8]> {
+    let mut line = Cow::Borrowed(line);
+
+    // -w drops all the whitespace -b would have squeezed, so it wins.
+    if opts.ignore_all_space {
+        line = Cow::Owned(line.iter().filter(|c| !c.is_ascii_whitespace()).copied().collect());
+    } else if opts.ignore_space_change {
+        line = Cow::Owned(squeeze_space(&line));
+    }
+    if opts.ignore_case {
+        line = Cow::Owned(line.to_ascii_lowercase());
+    }
+
+    line
+}
+
+/// Collapse each run of whitespace into a single space, and drop it
+/// entirely at either end of the line.
+fn squeeze_space(line: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(line.len());
+
+    for word in line.split(u8::is_ascii_whitespace).filter(|w| !w.is_empty()) {
+        if !out.is_empty() {
+            out.push(b' ');
+        }
+        out.extend_from_slice(word);
+    }
+
+    out
+}
+
+/// Flag the changes that don't count as differences under `opts`: those
+/// where every line added or removed is blank with -B, or matches an -I
+/// pattern. The two combine, so a change mixing blank and matching lines
+/// is ignored too. Lines are matched as they are in the file, whatever -i,
+/// -b or -w say.
+pub fn ignore_changes<T: AsRef<[u8]> + ?Sized>(d: &mut [Diff], a: &[&T], b: &[&T], opts: &DiffOptions) {
+    let ignorable = |lines: &[&T]| lines.iter().all(|l| ignorable(l.as_ref(), opts));
+
+    for t in d {
+        t.ignore = ignorable(&a[t.a - 1..t.b]) && ignorable(&b[t.c - 1..t.d]);
+    }
+}
+
+/// Whether a line is one -B or -I says to ignore.
+fn ignorable(line: &[u8], opts: &DiffOptions) -> bool {
+    (opts.ignore_blank_lines && line.iter().all(u8::is_ascii_whitespace))
+        || opts.ignore_matching_lines.iter().any(|r| r.is_match(line))
+}
+
+/// One line of a hunk.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Line<'a, T: ?Sized = str> {
+    /// Unchanged, in both files.
+    Context(&'a T),
+
+    /// Only in the old file.
+    Remove(&'a T),
+
+    /// Only in the new file.
+    Add(&'a T),
+}
+
+/// A run of changes close enough to share their context, as in a unified
+/// diff. Starts are 1-based; an empty range starts after the line it
+/// follows, so a hunk inserting at the top of a file has `old_start` 1
+/// and `old_len` 0.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Hunk<'a, T: ?Sized = str> {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+
+    /// The hunk's lines, in order: context, then each change's removed
+    /// lines followed by its added ones.
+    pub lines: Vec<Line<'a, T>>,
+}
+
+/// Group the changes `d` from `a` to `b` into hunks with `context` lines
+/// around them. Groups made only of ignored changes are left out.
+pub fn hunks<'a, T: ?Sized>(a: &[&'a T], b: &[&'a T], d: &[Diff], context: usize) -> Vec<Hunk<'a, T>> {
+    let mut hunks = vec![];
+    let mut i = 0;
+
+    while i < d.len() {
+        // Changes whose context would touch or overlap share a hunk.
+        let mut j = i;
+        while j + 1 < d.len() && d[j + 1].a - d[j].b - 1 <= context.saturating_mul(2) {
+            j += 1;
+        }
+
+        // Only show ignored changes when they share a hunk with a real one.
+        if d[i..=j].iter().all(|t| t.ignore) {
+            i = j + 1;
+            continue;
+        }
+
+        // Trim the context to the start and end of the file.
+        let start1 = d[i].a.saturating_sub(context).max(1);
+        let end1 = d[j].b.saturating_add(context).min(a.len());
+        let start2 = d[i].c - (d[i].a - start1);
+        let end2 = d[j].d + (end1 - d[j].b);
+
+        let mut lines = vec![];
+        let mut next = start1;
+        for t in &d[i..=j] {
+            lines.extend(a[next - 1..t.a - 1].iter().map(|l| Line::Context(*l)));
+            lines.extend(a[t.a - 1..t.b].iter().map(|l| Line::Remove(*l)));
+            lines.extend(b[t.c - 1..t.d].iter().map(|l| Line::Add(*l)));
+            next = t.b + 1;
+        }
+        lines.extend(a[next - 1..end1].iter().map(|l| Line::Context(*l)));
+
+        hunks.push(Hunk {
+            old_start: start1,
+            old_len: end1 + 1 - start1,
+            new_start: start2,
+            new_len: end2 + 1 - start2,
+            lines,
+        });
+
+        i = j + 1;
+    }
+
+    hunks
+}
+
+/// Drop context lines from the start and end of a hunk that -B or -I would
+/// ignore, leaving the changes themselves alone.
+pub fn trim_context<T: AsRef<[u8]> + ?Sized>(h: &mut Hunk<T>, opts: &DiffOptions) {
+    let trim = |l: &Line<T>| matches!(l, Line::Context(v) if ignorable(v.as_ref(), opts));
+
+    let lead = h.lines.iter().take_while(|l| trim(l)).count();
+    let trail = h.lines[lead..].iter().rev().take_while(|l| trim(l)).count();
+
+    h.lines.truncate(h.lines.len() - trail);
+    h.lines.drain(..lead);
+    h.old_start += lead;
+    h.new_start += lead;
+    h.old_len -= lead + trail;
+    h.new_len -= lead + trail;
+}
+
+/// Diff two files given as lines without their terminators, and group the
+/// changes into hunks.
+pub fn diff_lines<'a>(a: &[&'a str], b: &[&'a str], opts: &DiffOptions) -> Vec<Hunk<'a>> {
+    let keys = [a, b].map(|f| {
+        f.iter().map(|l| canon(l.as_bytes(), opts)).collect::<Vec<_>>()
+    });
+
+    let mut d = diff(&keys[0], &keys[1], opts);
+
+    if opts.ignore_blank_lines || !opts.ignore_matching_lines.is_empty() {
+        ignore_changes(&mut d, a, b, opts);
+    }
+
+    let mut hunks = hunks(a, b, &d, opts.context);
+    if opts.minimal_context {
+        hunks.iter_mut().for_each(|h| trim_context(h, opts));
+    }
+
+    hunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ignored(a: &[&str], b: &[&str], opts: &DiffOptions) -> Vec<bool> {
+        let mut d = diff(a, b, opts);
+        ignore_changes(&mut d, a, b, opts);
+
+        d.iter().map(|v| v.ignore).collect()
+    }
+
+    fn changed(d: &[Diff]) -> usize {
+        d.iter().map(|v| (v.b + 1 - v.a) + (v.d + 1 - v.c)).sum()
+    }
+
+    #[test]
+    fn minimal() {
+        // Nothing in common but a block that's at the end of one and the
+        // start of the other, far enough apart that the default search
+        // gives up before it finds it.
+        let a: Vec<usize> = (0..4200).map(|i| i + 1_000_000).chain(0..50).collect();
+        let b: Vec<usize> = (0..50).chain((0..4200).map(|i| i + 2_000_000)).collect();
+
+        let default = changed(&diff(&a, &b, &DiffOptions::default()));
+        let minimal = changed(&diff(&a, &b, &DiffOptions::new().minimal(true)));
+        assert_eq!(minimal, 8400);
+        assert!(default > minimal, "{} > {}", default, minimal);
+    }
+
+    /// Not a test so much as a benchmark: run it with
+    /// `cargo test --release --lib -- --ignored --nocapture speed`.
+    #[test]
+    #[ignore]
+    fn speed_large_files() {
+        use std::time::Instant;
+
+        // A million lines, with a few changed, a few gone and a few added.
+        let a: Vec<String> = (0..1_000_000).map(|i| format!("line {}", i)).collect();
+        let mut b = a.clone();
+        for i in (0..b.len()).step_by(100_003) {
+            b[i] = format!("changed {}", i);
+        }
+        b.drain(500_000..500_010);
+        b.splice(750_000..750_000, (0..10).map(|i| format!("added {}", i)));
+
+        for opts in [DiffOptions::default(), DiffOptions::new().speed_large_files(true)] {
+            let start = Instant::now();
+            let d = diff(&a, &b, &opts);
+            println!("speed_large_files={}: {} changes, {} lines, {:?}",
+                opts.speed_large_files, d.len(), changed(&d), start.elapsed());
+            assert_eq!(changed(&d), 2 * 10 + 10 + 10);
+        }
+    }
+
+    #[test]
+    fn ignore_matching_lines() {
+        let opts = DiffOptions::new().ignore_matching_lines(Regex::new(r"\$Id").unwrap());
+
+        // Every changed line matches, so the change is ignored.
+        let a = ["one", "$Id: 1 $", "two"];
+        let b = ["one", "$Id: 2 $", "two"];
+        assert_eq!(ignored(&a, &b, &opts), [true]);
+
+        // One that doesn't match keeps it.
+        let a = ["one", "$Id: 1 $", "two"];
+        let b = ["one", "$Id: 2 $", "three"];
+        assert_eq!(ignored(&a, &b, &opts), [false]);
+
+        // With -B, blank lines count as matching too.
+        let opts = opts.ignore_blank_lines(true);
+        let a = ["one", "$Id: 1 $", "two"];
+        let b = ["one", "$Id: 2 $", "", "two"];
+        assert_eq!(ignored(&a, &b, &opts), [true]);
+    }
+}
@@ -0,0 +1,8 @@
+//! The engines behind the diff and patch binaries, for use without
+//! shelling out to them.
+
+pub mod apply;
+pub mod engine;
+
+pub use apply::{apply_unified, Conflict, PatchOptions, Stats};
+pub use engine::{diff_lines, DiffOptions, Hunk, Line};
@@ -1,43 +1,49 @@
 mod common;
 
 use crate::common::*;
-use clap::Parser;
+use clap::{ArgEnum, Parser};
 use anyhow::{anyhow, Result};
-use log::debug;
-use peeking_take_while::PeekableExt;
-use std::cmp::Ordering;
 use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::env;
+use std::fmt;
 use std::fs;
 use std::fs::{File, OpenOptions};
 use std::io;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::iter::Peekable;
 use std::path::{Path, PathBuf};
+use std::process;
+use toyrust::apply::{byte_lines, hunk_range, HunkLine, HunkReader, Outcome, PatchOptions, Patcher};
 
-/// Apply a unified diff to one or more files.
+/// Apply a unified or context diff to one or more files.
 ///
-/// This version of patch only handles unified diffs, and only modifies
+/// This version of patch only handles unified and context diffs (the
+/// format is detected from the file headers), and only modifies
 /// a file when all hunks to that file apply. Patch prints failed hunks
 /// to stderr, and exits with nonzero status if any hunks fail.
 ///
 /// A file compared against `/dev/null` (or with a date <= the epoch) is
-/// created/deleted as appropriate.
+/// created/deleted as appropriate. git's `diff --git` headers are understood
+/// too, so renames and mode changes are applied along with the hunks.
 #[derive(Default, Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[clap(name = "patch", author, version, about, long_about = None)]
 struct PatchToy {
     /// Modify files in `dir`
     #[clap(short)]
     dir: Option<PathBuf>,
 
-    /// Input patch file (default = stdin)
-    #[clap(short)]
-    input: Option<PathBuf>,
+    /// Input patch file (default = stdin), repeat to apply several in turn
+    #[clap(short, multiple_occurrences(true))]
+    input: Vec<PathBuf>,
 
-    // Loose match (ignore whitespace)
+    /// Loose match (ignore whitespace)
     #[clap(short)]
-    loose: Option<bool>,
+    loose: bool,
 
-    /// Number of '/' to strip from start of file paths (default = all)
+    /// Number of leading path components to strip from file paths in the
+    /// patch (default = all, leaving the file name). git's a/ and b/
+    /// prefixes are one component, so git patches want -p1.
     #[clap(short = 'p')]
     strip: Option<usize>,
 
@@ -45,15 +51,33 @@ struct PatchToy {
     #[clap(short = 'R')]
     reverse: bool,
 
+    /// Save the original of each patched file to `file.orig`
+    #[clap(short, long)]
+    backup: bool,
+
+    /// How -b names backups: simple (`file.orig`), numbered (`file.~1~`,
+    /// `file.~2~`...), or existing (numbered if there already are some)
+    #[clap(short = 'V', long, arg_enum, value_name = "METHOD", default_value = "simple")]
+    version_control: Backups,
+
     /// Fuzz
     #[clap(short = 'F')]
     fuzz: Option<usize>,
 
+    /// Look for each hunk after the function its @@ line names (from
+    /// diff -p) first, before anywhere else it fits
+    #[clap(long)]
+    function_anchor: bool,
+
     /// Silent except for errors
     #[clap(short)]
     silent: bool,
 
-    /// Ignored (only handles "unified" diffs)
+    /// Say where every hunk went, and show how each was looked for
+    #[clap(short, long)]
+    verbose: bool,
+
+    /// Ignored (the diff format is detected from its headers)
     #[clap(short)]
     _unified: bool,
 
@@ -61,797 +85,1728 @@ struct PatchToy {
     #[clap(long)]
     dry_run: bool,
 
+    /// Remove files that are empty once patched
+    #[clap(short = 'E', long)]
+    remove_empty_files: bool,
+
+    /// Don't apply any more patch files after one fails
+    #[clap(long)]
+    stop_on_error: bool,
+
+    /// Apply the hunks that fit even when others in the same file fail
+    #[clap(short, long)]
+    force: bool,
+
+    /// Write the patched file to `file`, leaving the original alone
+    #[clap(short, long, value_name = "file")]
+    output: Option<PathBuf>,
+
+    /// Apply git binary patches
+    #[clap(long)]
+    binary: bool,
+
+    /// Patch files even if they look binary
+    #[clap(short = 'a', long)]
+    text: bool,
+
+    /// Apply patches that look reversed the other way around, without asking
+    #[clap(long)]
+    reverse_auto: bool,
+
+    /// Put hunks that fail in the file between conflict markers
+    #[clap(long)]
+    merge: bool,
+
+    /// Fail on damaged patches rather than guess what they mean: a NUL
+    /// byte isn't read as a space, a --- or +++ line can't be left out, and
+    /// only /dev/null creates or removes a file, not an empty side of a hunk
+    #[clap(long)]
+    posix: bool,
+
+    /// Three-way merge hunks that fail with what the file has instead,
+    /// leaving conflict markers only where both changed the same lines
+    #[clap(long = "3way", conflicts_with = "merge")]
+    three_way: bool,
+
+    /// Save rejected hunks to `file` (default = target file + ".rej")
+    #[clap(short, long, value_name = "file")]
+    reject_file: Option<PathBuf>,
+
     /// Pairs of file and patch to apply.
     #[clap(parse(from_os_str))]
     files: Vec<PathBuf>,
 }
 
+/// How backups are named, GNU patch's -V. The names it takes from Emacs
+/// work too.
+#[derive(ArgEnum, Default, Clone, Copy, PartialEq, Debug)]
+enum Backups {
+    #[default]
+    #[clap(alias = "never")]
+    Simple,
+    #[clap(alias = "t")]
+    Numbered,
+    #[clap(alias = "nil")]
+    Existing,
+}
+
 #[derive(Default, Debug)]
 struct Globals<'a> {
     i: Option<&'a PathBuf>,
-    d: Option<&'a str>,
-
-    p: usize,
-    g: usize,
-    f: usize,
 
-    current_hunk: VecDeque<String>,
+    current_hunk: HunkReader,
     oldline: usize,
-    oldlen: usize,
     newline: usize,
-    newlen: usize,
-    linenum: isize,
-    outnum: isize,
-
-    context: usize,
-    state: u32,
-    filein: Option<File>,
-    fileout: Option<File>,
+
+    /// The patch to this file looked reversed, so it's applied the other
+    /// way around from what -R says.
+    reversed: bool,
+
+    /// The file being patched, copied to its temp file as hunks apply.
+    file: Option<Patcher<BufReader<File>, File>>,
     hunknum: isize,
+
+    /// How far the last hunk to apply was from where it said it'd be.
+    offset: isize,
+
+    /// The function named in the last hunk header of this file.
+    function: String,
+
     tempname: Option<PathBuf>,
     destname: Option<PathBuf>,
 
-    exitval: Option<i32>,
+    /// Permissions to give the file once it's patched, from a git
+    /// "new file mode" or "new mode" line.
+    mode: Option<u32>,
+
+    /// The file a git patch renamed to this one, removed once it's patched.
+    renamed: Option<PathBuf>,
+
+    /// Header lines of the current file, and of the hunk being read, to
+    /// copy into the reject file.
+    filehead: String,
+    hunkhead: String,
+
+    /// Where rejected hunks of the current file go, and the file already
+    /// open for them.
+    rejname: Option<PathBuf>,
+    rejfile: Option<(PathBuf, File)>,
+
+    /// Hunks of the current file that failed.
+    rejects: usize,
+
+    /// Files opened for patching so far. -o only has room for one.
+    patched: usize,
+
+    /// A hunk of the current file failed, so it's left alone and its
+    /// remaining hunks are rejected without trying them (unless --force or
+    /// --dry-run says to carry on).
+    rejecting: bool,
+
+    /// Hunks and files that couldn't be patched.
+    failed: usize,
+
+    /// Hunks that applied.
+    applied: usize,
+
+    /// Each file patched, with how many of its hunks there were and how
+    /// many failed.
+    results: Vec<(PathBuf, isize, usize)>,
+
+    /// With --dry-run, each hunk of the current file, the line it applied
+    /// or would fail at, and whether it applied.
+    checked: Vec<(isize, usize, bool)>,
 }
 
-/// Dispose of a line of input, either by writing it out or discarding it.
-///
-/// state < 2: just free
-///
-/// state = 2: write whole line to stderr
-///
-/// state = 3: write whole line to fileout
-///
-/// state > 3: write line+1 to fileout when *line != state
-pub fn do_line(outnum: &mut isize, state: &mut u32, fileout: &mut Option<File>, data: &str) -> Result<()> {
-    *outnum += 1;
-    if *state > 1 {
-        if *state == 2 {
-            if *state > 3 {
-                eprintln!("{}", &data[1..]);
-            } else {
-                eprintln!("{}", &data[0..]);
-            }
-        } else {
-            let mut f = fileout.as_ref().unwrap();
-            if *state > 3 {
-                writeln!(f, "{}", &data[1..])?;
-            } else {
-                writeln!(f, "{}", &data[0..])?;
-            }
+/// Why patch didn't do everything it was asked to. What went wrong has
+/// already been reported by then, so this only decides the exit status.
+#[derive(Debug)]
+enum PatchError {
+    Failed { count: usize },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::Failed { count } => write!(f, "{} hunks or files couldn't be patched", count),
         }
     }
+}
 
-    debug!("DO {}: {}", state, data);
+impl std::error::Error for PatchError {}
 
-    Ok(())
+/// With -v, the hunk matching's debug output goes to stderr.
+struct Verbose;
+
+impl log::Log for Verbose {
+    fn enabled(&self, _: &log::Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &log::Record) {
+        eprintln!("{}", record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+static VERBOSE: Verbose = Verbose;
+
+/// What a git header says about a file, besides its names.
+#[derive(Default, Debug)]
+struct GitHeader {
+    rename: bool,
+    oldmode: Option<u32>,
+    newmode: Option<u32>,
+
+    /// The lines of a "GIT binary patch", which takes the place of hunks.
+    binary: Option<Vec<String>>,
+}
+
+impl GitHeader {
+    /// The mode the file ends up with, going whichever way we're patching.
+    fn mode(&self, reverse: bool) -> Option<u32> {
+        match reverse {
+            true => self.oldmode,
+            false => self.newmode,
+        }
+    }
+}
+
+/// Don't leave a half written copy behind if we bail out partway through.
+impl Drop for Globals<'_> {
+    fn drop(&mut self) {
+        if let Some(v) = self.tempname.take() {
+            let _ = fs::remove_file(v);
+        }
+    }
 }
 
 impl Globals<'_> {
-    /// Copy the rest of the data and replace the original with the copy.
-    pub fn finish_oldfile(&mut self) -> Result<()> {
+    /// Copy the rest of the data and replace the original with the copy,
+    /// backing the original up first with -b.
+    pub fn finish_oldfile(&mut self, toy: &PatchToy) -> Result<()> {
+        // With -E, a file patched down to nothing goes away. A dry run only
+        // says so.
+        let mut empty = false;
+        if let Some(mut v) = self.file.take() {
+            v.copy_rest()?;
+            empty = toy.remove_empty_files && self.rejects == 0 && v.written() == 0;
+
+            // Anything written past the end by a hunk that was started
+            // over with --merge goes.
+            let (_, mut fileout) = v.into_inner();
+            if self.tempname.is_some() {
+                let len = fileout.stream_position()?;
+                fileout.set_len(len)?;
+            }
+        }
+        if let (true, Some(v)) = (empty && !toy.silent, &self.destname) {
+            println!("removing {}", v.to_string_lossy());
+        }
+
         if self.tempname.is_some() {
-            if self.filein.is_some() {
-                let mut a = self
-                    .filein
-                    .as_ref()
-                    .ok_or_else(|| anyhow!("filein unavailable"))?;
-                let mut b = self
-                    .fileout
-                    .as_ref()
-                    .ok_or_else(|| anyhow!("fileout unavailable"))?;
-                io::copy(&mut a, &mut b)?;
-            }
-
-            fs::rename(
-                self.tempname
-                    .as_ref()
-                    .ok_or_else(|| anyhow!("tempname unset?!"))?,
-                self.destname
-                    .as_ref()
-                    .ok_or_else(|| anyhow!("destname unset?!"))?,
-            )?;
+            let destname = self
+                .destname
+                .as_ref()
+                .ok_or_else(|| anyhow!("destname unset?!"))?;
+
+            // fs::copy brings the permissions along, like copy_tempfile.
+            if toy.backup && destname.exists() {
+                fs::copy(destname, backup_name(destname, toy.version_control)?)?;
+            }
+
+            let tempname = self
+                .tempname
+                .take()
+                .ok_or_else(|| anyhow!("tempname unset?!"))?;
+
+            if empty {
+                fs::remove_file(tempname)?;
+                if destname.exists() {
+                    fs::remove_file(destname)?;
+                }
+            } else {
+                rename(&tempname, destname)?;
+
+                #[cfg(unix)]
+                if let Some(v) = self.mode {
+                    use std::os::unix::fs::PermissionsExt;
+
+                    fs::set_permissions(destname, fs::Permissions::from_mode(v & 0o7777))?;
+                }
+            }
+
+            if let Some(v) = self.renamed.take() {
+                fs::remove_file(v)?;
+            }
+        }
+
+        self.mode = None;
+        self.renamed = None;
+        self.file = None;
+
+        if let Some(v) = self.destname.take() {
+            self.results.push((v, self.hunknum, self.rejects));
+        }
+
+        for (n, line, ok) in self.checked.drain(..) {
+            match ok {
+                true if toy.silent => (),
+                true => println!("would apply hunk {}/{} at line {}", n, self.hunknum, line),
+                false => println!("hunk {}/{} would FAIL at line {}", n, self.hunknum, line),
+            }
+        }
+        if self.rejects > 0 && !toy.dry_run {
+            eprint!("{} out of {} hunks FAILED", self.rejects, self.hunknum);
+            match &self.rejname {
+                Some(v) => eprintln!(" -- saving rejects to file {}", v.to_string_lossy()),
+                None => eprintln!(),
+            }
+        }
+        self.rejects = 0;
+        self.rejecting = false;
+
+        // Whatever the next file is, its hunks start from the beginning.
+        self.reversed = false;
+        self.hunknum = 0;
+        self.offset = 0;
+        self.function.clear();
+
+        Ok(())
+    }
+
+    /// Carry out a git header that no hunks followed: a rename, a mode
+    /// change, a binary patch, or creating or deleting an empty file.
+    pub fn finish_git(
+        &mut self,
+        toy: &PatchToy,
+        oldname: Option<&Path>,
+        newname: Option<&Path>,
+        head: &GitHeader,
+    ) -> Result<()> {
+        let (from, to) = match (toy.reverse, oldname, newname) {
+            (false, Some(a), Some(b)) => (a, b),
+            (true, Some(a), Some(b)) => (b, a),
+            _ => return Ok(()),
+        };
+
+        // handle -p path truncation, leaving /dev/null alone.
+        let strip = |name: &Path| match name == devnull() {
+            true => Some(name.to_path_buf()),
+            false => strip_path(name, toy.strip),
+        };
+        let (from, to) = match (strip(from), strip(to)) {
+            (Some(a), Some(b)) => (a, b),
+            _ => {
+                eprintln!("can't strip {} leading components from {}",
+                    toy.strip.unwrap_or_default(), from.to_string_lossy());
+                self.failed += 1;
+                return Ok(());
+            }
+        };
+
+        // With -o, whatever we'd do to the file lands in the output instead.
+        let output = match &toy.output {
+            None => None,
+            Some(v) if self.patched > 0 => {
+                eprintln!("can't write more than one patched file to {}", v.to_string_lossy());
+                self.failed += 1;
+                return Ok(());
+            }
+            Some(v) => {
+                self.patched += 1;
+                Some(v.as_path())
+            }
+        };
+
+        // Work out a binary patch's new contents before touching anything,
+        // so a bad one leaves the file alone.
+        let data = match &head.binary {
+            None => None,
+            Some(v) => {
+                let name = match to == devnull() {
+                    true => &from,
+                    false => &to,
+                };
+                let data = match toy.binary {
+                    true => binary_patch(v, toy.reverse, &from),
+                    false => Err(anyhow!("binary patch needs --binary")),
+                };
+
+                match data {
+                    Ok(v) => Some(v),
+                    Err(e) => {
+                        eprintln!("can't patch {}: {}", name.to_string_lossy(), e);
+                        self.failed += 1;
+                        return Ok(());
+                    }
+                }
+            }
+        };
+
+        if to == devnull() {
+            if !toy.silent {
+                println!("removing {}", from.to_string_lossy());
+            }
+            if !toy.dry_run {
+                match output {
+                    Some(v) => drop(File::create(v)?),
+                    None => fs::remove_file(&from)?,
+                }
+            }
+            return Ok(());
+        }
+
+        if from == devnull() {
+            if !toy.silent {
+                println!("creating {}", to.to_string_lossy());
+            }
+            if !toy.dry_run {
+                let to = output.unwrap_or(&to);
+                make_parents(to)?;
+                File::create(to)?;
+            }
+        } else if head.rename && from != to {
+            if !toy.silent {
+                println!("renaming {} to {}", from.to_string_lossy(), to.to_string_lossy());
+            }
+            if !toy.dry_run {
+                let to = output.unwrap_or(&to);
+                make_parents(to)?;
+                match output {
+                    Some(v) => drop(fs::copy(&from, v)?),
+                    None => rename(&from, to)?,
+                }
+            }
+        } else if (data.is_some() || head.mode(toy.reverse).is_some()) && !toy.silent {
+            println!("patching {}", to.to_string_lossy());
+        }
+
+        if toy.dry_run {
+            return Ok(());
+        }
+
+        let to = match output {
+            Some(v) if !head.rename && (data.is_some() || head.mode(toy.reverse).is_some()) => {
+                fs::copy(&from, v)?;
+                v
+            }
+            Some(v) => v,
+            None => &to,
+        };
 
-            self.tempname = None;
+        if let Some(v) = data {
+            fs::write(to, v)?;
         }
 
-        self.fileout = None;
-        self.filein = None;
+        #[cfg(unix)]
+        if let Some(v) = head.mode(toy.reverse) {
+            use std::os::unix::fs::PermissionsExt;
+
+            fs::set_permissions(to, fs::Permissions::from_mode(v & 0o7777))?;
+        }
 
         Ok(())
     }
 
-    /// TODO: export failed hunk before closing
+    /// Report a hunk that didn't apply and save it to the reject file, then
+    /// discard this file's changes.
     pub fn fail_hunk(&mut self, toy: &PatchToy) -> Result<()> {
-        if self.current_hunk.is_empty() {
+        if self.current_hunk.lines.is_empty() {
             return Ok(());
         }
 
-        eprintln!(
-            "Hunk {} FAILED {}/{}.",
-            self.hunknum, self.oldline, self.newline
-        );
+        // A dry run reports all its hunks once the file's done.
+        if !toy.dry_run {
+            eprintln!(
+                "Hunk {} FAILED {}/{}.",
+                self.hunknum, self.oldline, self.newline
+            );
+        }
+
+        self.failed += 1;
+
+        if let Some(v) = &self.rejname {
+            if self.rejfile.as_ref().map(|f| &f.0) != Some(v) {
+                self.rejfile = Some((v.clone(), File::create(v)?));
+            }
 
-        self.exitval = Some(1);
+            let mut f = &self.rejfile.as_ref().ok_or_else(|| anyhow!("reject file unavailable"))?.1;
+            if self.rejects == 0 {
+                write!(f, "{}", self.filehead)?;
+            }
+            writeln!(f, "{}", self.hunkhead)?;
+            for line in &self.current_hunk.lines {
+                f.write_all(line)?;
+                f.write_all(b"\n")?;
+            }
+        }
+        self.rejects += 1;
 
         // If we got to this point, we've seeked to the end.  Discard changes to
         // this file and advance to next file.
 
-        self.state = 2;
-        self.current_hunk.clear();
-        if !toy.dry_run {
-            self.filein = None;
-            self.fileout = None;
+        self.current_hunk.lines.clear();
+        if !toy.dry_run && !toy.force && !self.rejecting {
+            self.file = None;
             std::fs::remove_file(
-                self.tempname.as_ref()
+                self.tempname.take()
                     .ok_or_else(|| anyhow!("No temp file to remove"))?,
             )?;
         }
-        self.state = 0;
+        self.rejecting = true;
 
         Ok(())
     }
 
-    /// Given a hunk of a unified diff, make the appropriate change to the file.
-    /// This does not use the location information, but instead treats a hunk
-    /// as a sort of regex. Copies data from input to output until it finds
-    /// the change to be made, then outputs the changed data and returns.
-    /// (Finding EOF first is an error.) This is a single pass operation, so
-    /// multiple hunks must occur in order in the file.
-    pub fn apply_one_hunk(&mut self, toy: &PatchToy) -> Result<u32> {
-        // struct double_list *plist, *buf = 0, *check;
-        let mut trail = 0;
-        let reverse = toy.reverse;
-        let mut backwarn = 0;
-        let mut allfuzz = 0;
-        let mut fuzz = 0;
-        let mut i = 0;
-
-        let lcmp = |aa: &str, bb: &str| {
-            match toy.loose {
-                Some(_) => loosecmp(aa, bb),
-                None => aa.cmp(bb)
-            }
-        };
+    /// Ask whether to apply a patch that looks reversed the other way
+    /// around. Only asks when there's someone at stdin to answer, and stdin
+    /// isn't where the patch is coming from.
+    fn assume_reversed(&self, toy: &PatchToy) -> Result<bool> {
+        if toy.reverse_auto {
+            return Ok(true);
+        }
+        if self.i.is_none() || !atty::is(atty::Stream::Stdin) {
+            return Ok(false);
+        }
 
-        // Match EOF if there aren't as many ending context lines as beginning
-        {
-            fuzz = 0;
-            for plist in &self.current_hunk {
-                let c = plist;
+        match toy.reverse {
+            true => eprint!("Unreversed patch detected!  Ignore -R? [n] "),
+            false => eprint!("Reversed (or previously applied) patch detected!  Assume -R? [n] "),
+        }
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
 
-                match c.starts_with(" ") {
-                    true => trail += 1,
-                    false => trail = 0,
-                }
+        Ok(answer.trim_start().starts_with(['y', 'Y']))
+    }
 
-                // Only allow fuzz if 2 context lines have multiple nonwhitespace chars.
-                // avoids the "all context was blank or } lines" issue. Removed lines
-                // count as context since they're matched.
-                if c.starts_with(" ")
-                    || c.starts_with(|d| match reverse {
-                        true => d == '+',
-                        false => d == '-',
-                    })
-                {
-                    let mut s = plist[1..].chars().skip_while(|c| c.is_ascii_whitespace());
-                    
-                    match s.nth(1) {
-                        Some(v) => {
-                            if !v.is_ascii_whitespace() {
-                                fuzz += 1;
-                            }
-                        }
-                        None => {}
-                    };
-                }
+    /// A hunk didn't apply, but the lines it adds were already there. If it
+    /// is the file's first hunk, nothing has been written yet, so the file
+    /// can start over with the patch reversed. Returns whether it did.
+    fn retry_reversed(&mut self, toy: &PatchToy, backwarn: Option<usize>) -> Result<bool> {
+        let backwarn = match backwarn {
+            Some(v) => v,
+            None => return Ok(false),
+        };
+        if !toy.silent {
+            eprintln!("Possibly reversed hunk {} at {}", self.hunknum, backwarn);
+        }
+        if self.hunknum != 1 || self.reversed || !self.assume_reversed(toy)? {
+            return Ok(false);
+        }
 
-                #[cfg(debug_assertions)]
-                eprintln!("HUNK:{}", plist);
+        if let Some(v) = self.file.take() {
+            let (mut filein, mut fileout) = v.into_inner();
+            filein.seek(SeekFrom::Start(0))?;
+            if self.tempname.is_some() {
+                fileout.set_len(0)?;
+                fileout.seek(SeekFrom::Start(0))?;
             }
+            self.file = Some(Patcher::new(filein, fileout));
         }
+        self.reversed = true;
 
-        let matcheof = trail == 0 || trail < self.context;
-        let _allfuzz = match fuzz.cmp(&2) {
-            Ordering::Less => 0,
-            _ => match toy.fuzz {
-                Some(v) => v,
-                None => match self.context.cmp(&0) {
-                    Ordering::Greater => self.context - 1,
-                    _ => 0
-                }
+        Ok(true)
+    }
+
+    /// Apply a complete hunk, or reject it if the file is already being
+    /// rejected. Returns the state to carry on in.
+    pub fn finish_hunk(&mut self, toy: &PatchToy) -> Result<u32> {
+        match self.rejecting && !toy.dry_run && !toy.force {
+            true => {
+                self.fail_hunk(toy)?;
+                Ok(0)
+            },
+            false => self.apply_one_hunk(toy),
+        }
+    }
+
+    /// Apply the current hunk to the file, or reject it if it doesn't
+    /// apply either way around. Returns the state to carry on in.
+    pub fn apply_one_hunk(&mut self, toy: &PatchToy) -> Result<u32> {
+        let mut opts = PatchOptions {
+            reverse: toy.reverse != self.reversed,
+            loose: toy.loose,
+            fuzz: toy.fuzz,
+            anchor: None,
+        };
+
+        // With --function-anchor, a hunk in a new function is looked for
+        // after that function's line first. One in the same function as
+        // the last hunk is already past it.
+        if toy.function_anchor {
+            let function = function_name(&self.hunkhead);
+            if !function.is_empty() && function != self.function {
+                opts.anchor = Some(function.as_bytes().to_vec());
             }
+            self.function = function.to_string();
+        }
+        let file = self.file.as_mut().ok_or_else(|| anyhow!("Unavailable input!"))?;
+
+        // With --merge or --3way, a hunk that fails starts over from here
+        // to leave conflict markers instead, and --force or a dry run go on to try
+        // the rest. So does one that wasn't found after its function, to
+        // look for it anywhere.
+        let mark = match toy.merge || toy.three_way || toy.force || toy.dry_run || opts.anchor.is_some() {
+            true => Some(file.mark()?),
+            false => None,
         };
 
-        #[cfg(debug_assertions)]
-        eprintln!("MATCHEOF={}", matcheof);
-
-        // Loop through input data searching for this hunk. Match all context
-        // lines and lines to be removed until we've found end of complete hunk.
-        let mut plist = &mut self.current_hunk;
-        let mut buf: Vec<String> = vec![];
-        let mut check: &[String];
-        let mut fuzz = 0;
-        let mut filein = match &self.filein {
-            Some(v) => BufReader::new(v).lines(),
-            None => return Err(anyhow!("Unavailable input!"))
+        // A hunk with no old lines goes after the line it names.
+        let (line, newline, add) = match opts.reverse {
+            true => (self.newline, self.oldline, b'-'),
+            false => (self.oldline, self.newline, b'+'),
         };
+        let line = line + self.current_hunk.lines.iter().all(|l| l.first() == Some(&add)) as usize;
 
-        loop {
-            let data = filein.next();
-
-            // Figure out which line of hunk to compare with next. (Skip lines
-            // of the hunk we'd be adding.)
-            while !plist.is_empty() {
-                match plist.front() {
-                    Some(v) => {
-                        let start = match reverse {
-                            true => '-',
-                            false => '+'
-                        };
-                        if v.starts_with(start) {
-                            match &data {
-                                Some(d) => {
-                                    if lcmp(d.as_ref().unwrap(), &v[1..]) == Ordering::Equal {
-                                        if backwarn == 0 {
-                                            backwarn = self.linenum;
-                                        }
-                                    }
-                                },
-                                None => {}
-                            }
+        let mut outcome = file.apply_hunk(&self.current_hunk.lines, &opts)?;
+        if let (Outcome::Failed { .. }, Some(v)) = (outcome, mark.filter(|_| opts.anchor.is_some())) {
+            file.rewind(&v)?;
+            opts.anchor = None;
+            outcome = file.apply_hunk(&self.current_hunk.lines, &opts)?;
+        }
+
+        match outcome {
+            Outcome::Applied { at } => {
+                // Say how far the hunk was from where it said it'd be.
+                let offset = at as isize - line as isize;
+                match offset {
+                    _ if toy.silent || toy.dry_run => (),
+                    0 if toy.verbose => println!("Hunk {} succeeded at {}.", self.hunknum, newline),
+                    0 => (),
+                    _ => println!("Hunk {} succeeded at {} (offset {} line{}).", self.hunknum,
+                        newline as isize + offset, offset, if offset.abs() == 1 { "" } else { "s" }),
+                }
+                if toy.dry_run {
+                    self.checked.push((self.hunknum, (newline as isize + offset) as usize, true));
+                }
+                self.offset = offset;
+                self.applied += 1;
+
+                self.current_hunk.lines.clear();
+                Ok(1)
+            },
+            Outcome::Failed { reversed_at } => {
+                if self.retry_reversed(toy, reversed_at)? {
+                    return self.apply_one_hunk(toy);
+                }
+                // Where the hunk would be if it had moved as far as the last
+                // one did.
+                let at = (line as isize + self.offset).max(1) as usize;
+                if toy.dry_run {
+                    self.checked.push((self.hunknum, at, false));
+                }
+                if let Some(v) = mark {
+                    let file = self.file.as_mut().ok_or_else(|| anyhow!("Unavailable input!"))?;
+                    file.rewind(&v)?;
+                    if toy.merge {
+                        eprintln!("Hunk {} NOT MERGED at {}.", self.hunknum, at);
+                        file.conflict(&self.current_hunk.lines, at, &opts)?;
+                        self.failed += 1;
+                        self.current_hunk.lines.clear();
+                        return Ok(1);
+                    }
+                    if toy.three_way {
+                        match file.merge(&self.current_hunk.lines, at, &opts)? {
+                            true => {
+                                if let (true, Some(v)) = (toy.dry_run, self.checked.last_mut()) {
+                                    v.2 = true;
+                                } else if !toy.silent {
+                                    println!("Hunk {} merged at {}.", self.hunknum, at);
+                                }
+                                self.applied += 1;
+                            },
+                            false => {
+                                eprintln!("Hunk {} NOT MERGED at {}.", self.hunknum, at);
+                                self.failed += 1;
+                            },
                         }
-                    },
-                    None => break
+                        self.current_hunk.lines.clear();
+                        return Ok(1);
+                    }
                 }
-                plist.pop_front();
-            }
+                self.fail_hunk(toy)?;
+                Ok(0)
+            },
+        }
+    }
+}
 
-            // Is this EOF?
-            match &data {
-                Some(v) => {
-                    self.linenum += 1;
+/// Rewrites the hunks of a context diff as unified ones, so the rest of
+/// patch only has to deal with one format. Anything that isn't part of a
+/// context diff passes through untouched.
+///
+/// A context hunk lists the old lines and then the new ones, either of
+/// which is left out when it would only repeat the other's context:
+///
+/// ```text
+/// ***************
+/// *** 12,14 ****
+///   context
+/// ! old line
+///   context
+/// --- 12,14 ----
+///   context
+/// ! new line
+///   context
+/// ```
+struct ContextToUnified<I: Iterator<Item = Vec<u8>>> {
+    lines: Peekable<I>,
+    out: VecDeque<Vec<u8>>,
+}
 
-                    #[cfg(debug_assertions)]
-                    eprintln!("IN: {:?}", v);
+impl<I: Iterator<Item = Vec<u8>>> ContextToUnified<I> {
+    fn new(lines: I) -> Self {
+        ContextToUnified {
+            lines: lines.peekable(),
+            out: VecDeque::new(),
+        }
+    }
 
-                    buf.push(v.as_ref().unwrap().clone());
+    /// Parse a context range, `first[,last]`, into a start and length. A
+    /// lone 0 is an empty file.
+    fn range(s: &[u8]) -> Option<(usize, usize)> {
+        match std::str::from_utf8(s).ok()?.split_once(',') {
+            Some((first, last)) => {
+                let first: usize = first.parse().ok()?;
+                Some((first, (last.parse::<usize>().ok()? + 1).checked_sub(first)?))
+            },
+            None => match std::str::from_utf8(s).ok()?.parse().ok()? {
+                0 => Some((0, 0)),
+                first => Some((first, 1)),
+            },
+        }
+    }
 
-                    check = buf.as_slice();
-                }, 
-                None => {
-                    #[cfg(debug_assertions)]
-                    eprintln!("INEOF");
+    /// Collect up to `len` lines of one side of a hunk, that start with one
+    /// of `markers` and a space.
+    fn section(&mut self, len: usize, markers: &[u8]) -> Vec<Vec<u8>> {
+        let mut section = vec![];
 
-                    // Does this hunk need to match EOF?
-                    if plist.is_empty() && matcheof {
-                        break;
-                    }
+        while section.len() < len {
+            match self.lines.peek().map(|l| l.as_slice()) {
+                Some([c, b' ', ..]) if markers.contains(c) => {},
+                _ => break,
+            }
+            section.extend(self.lines.next());
+        }
 
-                    if backwarn != 0 && toy.silent {
-                        eprintln!("Possibly reversed hunk {} at {}", self.hunknum, self.linenum);
-                    }
+        section
+    }
 
-                    // File ended before we found a place for this hunk.
-                    self.fail_hunk(toy)?;
-                    // done:
-                    for i in buf {
-                        do_line(&mut self.outnum, &mut self.state, &mut self.fileout, &i)?;
-                    }
-                    return Ok(self.state);
-                }
-            }
+    /// Read a hunk after its `***************` line, and queue it up in
+    /// unified form.
+    fn hunk(&mut self) -> Option<()> {
+        let head = self.lines.next()?;
+        let (oldline, oldlen) = Self::range(head.strip_prefix(b"*** ")?.strip_suffix(b" ****")?)?;
+        let mut old = self.section(oldlen, b" -!");
+
+        let head = self.lines.next()?;
+        let (newline, newlen) = Self::range(head.strip_prefix(b"--- ")?.strip_suffix(b" ----")?)?;
+
+        // A side with no changes of its own is left out, and is all context.
+        let context = |v: &Vec<Vec<u8>>| v.iter().filter(|l| l.starts_with(b" ")).cloned().collect::<Vec<_>>();
+        let mut new = match old.iter().any(|l| !l.starts_with(b" ")) || context(&old).len() != newlen {
+            true => self.section(newlen, b" +!"),
+            false => vec![],
+        };
+        if old.is_empty() {
+            old = context(&new);
+        }
+        if new.is_empty() {
+            new = context(&old);
+        }
 
-            // Compare this line with next expected line of hunk. Match can fail
-            // because next line doesn't match, or because we hit end of a hunk that
-            // needed EOF and this isn't EOF.
-            loop {
-                let a = check.first().ok_or_else(|| anyhow!("No line to process!"))?;
-                let b = plist.front().ok_or_else(|| anyhow!("No line to process!"))?;
-                if plist.is_empty() || lcmp(a, &b[1..]) != Ordering::Equal {
-                    // Match failed: can we fuzz it?
-                    match plist.front() {
-                        Some(d) => {
-                            if d.starts_with(|c: char| c.is_ascii_whitespace()) && fuzz < allfuzz {
-                                #[cfg(debug_assertions)]
-                                eprintln!("FUZZED: {} {}", self.linenum, d);
-
-                                fuzz += 1;
-
-                                // goto: fuzzed
-                                // This line matches. Advance plist, detect successful match.
-                                plist.pop_front();
-                                if plist.is_empty() && !matcheof {
-                                    // goto out;
-                                    // We have a match.  Emit changed data.
-                                    self.state = match reverse {
-                                        true => '+' as u32,
-                                        false => '-' as u32
-                                    };
-                                    for line in &self.current_hunk {
-                                        if line.starts_with(|c: char| c as u32 == self.state) || line.starts_with(|c: char| c.is_ascii_whitespace()) {
-                                            let t: Vec<_> = buf.drain(0..1).collect();
-                                            if line.starts_with(|c: char| c.is_ascii_whitespace()) {
-                                                let mut f = self.fileout.as_ref().unwrap();
-                                                for i in t {
-                                                    writeln!(f, "{}", i)?;
-                                                }
-                                            }
-                                        } else {
-                                            let mut f = self.fileout.as_ref().unwrap();
-                                            writeln!(f, "{}", &line[1..])?;
-                                        }
-                                    }
-                                    self.current_hunk.clear();
-                                    self.state = 1;
-                                    
-                                    for i in buf {
-                                        do_line(&mut self.outnum, &mut self.state, &mut self.fileout, &i)?;
-                                    }
-
-                                    return Ok(self.state);
-                                }
-                                check = &check[1..];
-                                if check == buf {
-                                    break;
-                                } 
-                            }
-                        },
-                        _ => {}
-                    }
+        self.out.push_back(format!("@@ -{},{} +{},{} @@", oldline, oldlen, newline, newlen).into_bytes());
 
-                    #[cfg(debug_assertions)]
-                    {
-                        let mut bug = 0;
-
-                        if plist.is_empty() {
-                            eprintln!("NULL plist");
-                        } else {
-                            let p = plist.front().ok_or_else(|| anyhow!("[DEBUG] No line to process!"))?;
-                            let mut a = check.first().ok_or_else(|| anyhow!("[DEBUG] No line to process!"))?.chars().peekable();
-                            let mut b = p.chars().peekable();
-                            while a.peek() == b.peek() {
-                                bug += 1;
-                                a.next();
-                                b.next();
-                            }
-                            eprintln!("NOT({}:{}!={}): {}", bug, &plist.front().unwrap()[bug..],
-                            &check.first().unwrap()[bug..], p);
-                        }
+        // Removed and added lines go as they come, and a block of changed
+        // lines turns into its old version removed and new version added.
+        let marker = |v: &Vec<Vec<u8>>, i: usize| v.get(i).map(|l| l[0]);
+        let (mut i, mut j) = (0, 0);
+        loop {
+            match (marker(&old, i), marker(&new, j)) {
+                (None, None) => break,
+                (Some(b'-'), _) => {
+                    self.out.push_back([b"-", &old[i][2..]].concat());
+                    i += 1;
+                },
+                (_, Some(b'+')) => {
+                    self.out.push_back([b"+", &new[j][2..]].concat());
+                    j += 1;
+                },
+                (Some(b'!'), _) | (_, Some(b'!')) => {
+                    while marker(&old, i) == Some(b'!') {
+                        self.out.push_back([b"-", &old[i][2..]].concat());
+                        i += 1;
                     }
-
-                    // If this hunk must match start of file, fail if it didn't.
-                    if self.context == 0 || trail > self.context {
-                        self.fail_hunk(toy)?;
-                        // done:
-                        for i in buf {
-                            do_line(&mut self.outnum, &mut self.state, &mut self.fileout, &i)?;
-                        }
-                        return Ok(self.state);
+                    while marker(&new, j) == Some(b'!') {
+                        self.out.push_back([b"+", &new[j][2..]].concat());
+                        j += 1;
                     }
+                },
+                _ => {
+                    let line = old.get(i).or_else(|| new.get(j))?;
+                    self.out.push_back([b" ", &line[2..]].concat());
+                    i += 1;
+                    j += 1;
+                },
+            }
+        }
 
-                    // Write out first line of buffer and recheck rest for new match.
-                    self.state = 3;
-                    check = &buf[1..];
-                    for i in check {
-                        do_line(&mut self.outnum, &mut self.state, &mut self.fileout, &i)?;
-                    }
-                    plist = &mut self.current_hunk;
-                    fuzz = 0;
+        Some(())
+    }
+}
 
-                    // If end of the buffer without finishing a match, read more lines.
-                    if buf.is_empty() {
-                        break;
-                    }
+impl<I: Iterator<Item = Vec<u8>>> Iterator for ContextToUnified<I> {
+    type Item = Vec<u8>;
 
-                    check = &buf;
-                } else {
-                    #[cfg(debug_assertions)]
-                    eprintln!("MAYBE: {:?}", plist.front());
-                    
-                    // fuzzed:
-                    // This line matches. Advance plist, detect successful match.
-                    plist.pop_front();
-                    if plist.is_empty() && !matcheof {
-                        // goto out;
-                        // We have a match.  Emit changed data.
-                        self.state = match reverse {
-                            true => '+' as u32,
-                            false => '-' as u32
-                        };
-                        for line in &self.current_hunk {
-                            if line.starts_with(|c: char| c as u32 == self.state) || line.starts_with(|c: char| c.is_ascii_whitespace()) {
-                                let t: Vec<_> = buf.drain(0..1).collect();
-                                if line.starts_with(|c: char| c.is_ascii_whitespace()) {
-                                    let mut f = self.fileout.as_ref().unwrap();
-                                    for i in t {
-                                        writeln!(f, "{}", i)?;
-                                    }
-                                }
-                            } else {
-                                let mut f = self.fileout.as_ref().unwrap();
-                                writeln!(f, "{}", &line[1..])?;
-                            }
-                        }
-                        self.current_hunk.clear();
-                        self.state = 1;
-                        
-                        for i in buf {
-                            do_line(&mut self.outnum, &mut self.state, &mut self.fileout, &i)?;
-                        }
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if let Some(v) = self.out.pop_front() {
+            return Some(v);
+        }
 
-                        return Ok(self.state);
-                    }
-                    check = &check[1..];
-                    if check == buf {
-                        break;
-                    } 
-                }
+        let line = self.lines.next()?;
+
+        // The file headers are "*** old" then "--- new".
+        if let Some(v) = line.strip_prefix(b"*** ") {
+            if !v.ends_with(b" ****") && self.lines.peek().is_some_and(|l| l.starts_with(b"--- ")) {
+                let new = self.lines.next()?;
+                self.out.push_back([b"+++ ", &new[4..]].concat());
+                return Some([b"--- ", v].concat());
             }
         }
-    // out:
-        // We have a match.  Emit changed data.
-        self.state = match reverse {
-            true => '+' as u32,
-            false => '-' as u32
-        };
-        for line in &self.current_hunk {
-            if line.starts_with(|c: char| c as u32 == self.state) || line.starts_with(|c: char| c.is_ascii_whitespace()) {
-                let t: Vec<_> = buf.drain(0..1).collect();
-                if line.starts_with(|c: char| c.is_ascii_whitespace()) {
-                    let mut f = self.fileout.as_ref().unwrap();
-                    for i in t {
-                        writeln!(f, "{}", i)?;
+
+        // A hunk that doesn't parse is dropped, like any other noise.
+        if line.starts_with(b"***************") {
+            self.hunk();
+            return self.next();
+        }
+
+        Some(line)
+    }
+}
+
+/// Decode a line of a git binary patch. The first character gives the
+/// number of bytes on the line (A-Z for 1-26, a-z for 27-52), and the rest
+/// is base 85, five characters to each four bytes.
+fn decode_base85(line: &str) -> Option<Vec<u8>> {
+    const DIGITS: &[u8] =
+        b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz!#$%&()*+-;<=>?@^_`{|}~";
+
+    let (len, data) = line.as_bytes().split_first()?;
+    let len = match len {
+        b'A'..=b'Z' => len - b'A' + 1,
+        b'a'..=b'z' => len - b'a' + 27,
+        _ => return None,
+    } as usize;
+    if data.len() % 5 != 0 || data.len() / 5 * 4 < len {
+        return None;
+    }
+
+    let mut out = Vec::with_capacity(data.len() / 5 * 4);
+    for group in data.chunks(5) {
+        let mut acc: u32 = 0;
+        for c in group {
+            let digit = DIGITS.iter().position(|v| v == c)? as u32;
+            acc = acc.checked_mul(85)?.checked_add(digit)?;
+        }
+        out.extend_from_slice(&acc.to_be_bytes());
+    }
+    out.truncate(len);
+
+    Some(out)
+}
+
+/// Read one of git's variable length sizes off the front of a delta.
+fn delta_size(delta: &mut impl Iterator<Item = u8>) -> Option<usize> {
+    let mut size = 0;
+    let mut shift = 0;
+
+    loop {
+        let c = delta.next()?;
+        size |= ((c & 0x7f) as usize).checked_shl(shift)?;
+        shift += 7;
+        if c & 0x80 == 0 {
+            return Some(size);
+        }
+    }
+}
+
+/// Rebuild a file from the old contents and a git delta, which is the two
+/// sizes followed by instructions to copy a range of the old contents, or
+/// to insert the bytes that follow.
+fn apply_delta(old: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let corrupt = || anyhow!("corrupt binary delta");
+    let mut delta = delta.iter().copied();
+
+    if delta_size(&mut delta).ok_or_else(corrupt)? != old.len() {
+        return Err(anyhow!("binary delta doesn't match the file"));
+    }
+    let size = delta_size(&mut delta).ok_or_else(corrupt)?;
+    let mut out = Vec::with_capacity(size);
+
+    while let Some(cmd) = delta.next() {
+        if cmd & 0x80 != 0 {
+            // Which bytes of the offset and length follow is a bitmask.
+            let mut arg = |bits: std::ops::Range<u32>| -> Result<usize> {
+                let mut v = 0;
+                for (i, bit) in bits.clone().enumerate() {
+                    if cmd & (1 << bit) != 0 {
+                        v |= (delta.next().ok_or_else(corrupt)? as usize) << (8 * i);
                     }
                 }
-            } else {
-                let mut f = self.fileout.as_ref().unwrap();
-                writeln!(f, "{}", &line[1..])?;
+                Ok(v)
+            };
+            let offset = arg(0..4)?;
+            let len = match arg(4..7)? {
+                0 => 0x10000,
+                v => v,
+            };
+            let range = old.get(offset..offset + len).ok_or_else(corrupt)?;
+            out.extend_from_slice(range);
+        } else if cmd != 0 {
+            for _ in 0..cmd {
+                out.push(delta.next().ok_or_else(corrupt)?);
             }
+        } else {
+            return Err(corrupt());
         }
-        self.current_hunk.clear();
-        self.state = 1;
-    // done:
-        for i in buf {
-            do_line(&mut self.outnum, &mut self.state, &mut self.fileout, &i)?;
+    }
+
+    match out.len() == size {
+        true => Ok(out),
+        false => Err(corrupt()),
+    }
+}
+
+/// Work out the new contents of a file from a "GIT binary patch". It has
+/// two sections, to go forwards and backwards, each a "literal" file or a
+/// "delta" from the file it's applied to, deflated and base 85 encoded.
+fn binary_patch(lines: &[String], reverse: bool, from: &Path) -> Result<Vec<u8>> {
+    let mut sections = lines.split(|v| v.is_empty()).filter(|v| !v.is_empty());
+    let section = match reverse {
+        true => sections.nth(1),
+        false => sections.next(),
+    }
+    .ok_or_else(|| anyhow!("binary patch can't be reversed"))?;
+
+    let (kind, size) = section[0]
+        .split_once(' ')
+        .ok_or_else(|| anyhow!("corrupt binary patch"))?;
+    let size: usize = size.parse()?;
+
+    let mut deflated = Vec::new();
+    for line in &section[1..] {
+        deflated.extend(decode_base85(line).ok_or_else(|| anyhow!("corrupt binary patch"))?);
+    }
+    let mut data = Vec::with_capacity(size);
+    flate2::read::ZlibDecoder::new(&deflated[..]).read_to_end(&mut data)?;
+    if data.len() != size {
+        return Err(anyhow!("corrupt binary patch"));
+    }
+
+    match kind {
+        "literal" => Ok(data),
+        "delta" => {
+            let old = match from == devnull() {
+                true => Vec::new(),
+                false => fs::read(from)?,
+            };
+            apply_delta(&old, &data)
         }
+        _ => Err(anyhow!("unknown binary patch type {}", kind)),
+    }
+}
 
-        return Ok(self.state);
+/// Where -b saves the original of `name`. A numbered backup is one past
+/// the highest already there, so earlier ones are kept.
+fn backup_name(name: &Path, scheme: Backups) -> Result<PathBuf> {
+    let file = name.file_name().ok_or_else(|| anyhow!("can't back up {}", name.to_string_lossy()))?;
+    let dir = match name.parent() {
+        Some(v) if v != Path::new("") => v,
+        _ => Path::new("."),
+    };
+    let prefix = format!("{}.~", file.to_string_lossy());
+    let last = match scheme {
+        Backups::Simple => None,
+        _ => fs::read_dir(dir)?
+            .filter_map(|v| v.ok())
+            .filter_map(|v| {
+                let v = v.file_name().to_string_lossy().into_owned();
+                v.strip_prefix(&prefix)?.strip_suffix('~')?.parse::<usize>().ok()
+            })
+            .max(),
+    };
+
+    let mut backup = name.as_os_str().to_owned();
+    match (scheme, last) {
+        (Backups::Simple, _) | (Backups::Existing, None) => backup.push(".orig"),
+        (_, n) => backup.push(format!(".~{}~", n.unwrap_or(0) + 1)),
     }
+
+    Ok(backup.into())
 }
 
-fn main() -> Result<()> {
-    let mut toy: PatchToy = PatchToy::from_args();
+/// Create the directories a new file at `name` goes in, if they're not
+/// there already.
+fn make_parents(name: &Path) -> Result<()> {
+    if let Some(v) = name.parent().filter(|v| !v.as_os_str().is_empty()) {
+        fs::create_dir_all(v)?;
+    }
 
-    let mut globals: Globals = Default::default();
+    Ok(())
+}
 
-    let _reverse = toy.reverse;
-    let mut state: u32 = 0;
-    let _patchlinenum: isize = 0;
-    let _strip: isize = 0;
+/// Guess whether a file is binary, from a NUL byte in its first 8KB, the
+/// way diff does. Leaves the file at the start.
+fn looks_binary(file: &mut File) -> Result<bool> {
+    let mut buf = vec![];
+    file.take(8192).read_to_end(&mut buf)?;
+    file.seek(SeekFrom::Start(0))?;
 
-    let mut oldname: Option<&Path> = None;
-    let mut newname: Option<&Path> = None;
+    Ok(buf.contains(&0))
+}
 
-    if toy.files.len() == 2 {
-        globals.i = Some(&toy.files[1]);
+/// Split the two file names off a `diff --git a/name b/name` line. The
+/// names can have spaces in them, so this looks for where the second one
+/// starts rather than splitting on whitespace.
+fn git_names(s: &str) -> Option<(PathBuf, PathBuf)> {
+    // Either name may be quoted.
+    if let Some((a, rest)) = unquote(s) {
+        let rest = rest.strip_prefix(' ')?;
+        let b = unquote(rest).map(|v| v.0).unwrap_or_else(|| PathBuf::from(rest));
+        return Some((a, b));
+    }
+    if let Some(i) = s.rfind(" \"") {
+        if let Some((b, "")) = unquote(&s[i + 1..]) {
+            return Some((PathBuf::from(&s[..i]), b));
+        }
     }
 
-    println!("{:?}", toy);
+    let (a, b) = match s.starts_with("a/") {
+        true => s.rfind(" b/").map(|i| (&s[..i], &s[i + 1..]))?,
+        // Without the prefixes the names are usually the same, so try
+        // splitting down the middle first.
+        false => match s.len() % 2 == 1 && s.as_bytes()[s.len() / 2] == b' ' {
+            true => (&s[..s.len() / 2], &s[s.len() / 2 + 1..]),
+            false => s.split_once(' ')?,
+        },
+    };
+
+    Some((PathBuf::from(a), PathBuf::from(b)))
+}
+
+/// Read a name in double quotes with C escapes, the way git and diff quote
+/// names with unusual characters in them, off the start of `s`. Returns
+/// the name and whatever follows the closing quote.
+fn unquote(s: &str) -> Option<(PathBuf, &str)> {
+    let mut bytes = s.strip_prefix('"')?.bytes().enumerate();
+    let mut name = vec![];
+
+    let end = loop {
+        let (i, c) = bytes.next()?;
+        match c {
+            b'"' => break i + 2,
+            b'\\' => {
+                let (_, c) = bytes.next()?;
+                name.push(match c {
+                    b'a' => 0x07,
+                    b'b' => 0x08,
+                    b't' => b'\t',
+                    b'n' => b'\n',
+                    b'v' => 0x0b,
+                    b'f' => 0x0c,
+                    b'r' => b'\r',
+                    b'0'..=b'7' => {
+                        let mut v = (c - b'0') as u32;
+                        for _ in 0..2 {
+                            match bytes.next()? {
+                                (_, d @ b'0'..=b'7') => v = v * 8 + (d - b'0') as u32,
+                                _ => return None,
+                            }
+                        }
+                        u8::try_from(v).ok()?
+                    },
+                    c => c,
+                });
+            },
+            c => name.push(c),
+        }
+    };
+
+    #[cfg(unix)]
+    let name = {
+        use std::os::unix::ffi::OsStringExt;
+
+        PathBuf::from(std::ffi::OsString::from_vec(name))
+    };
+    #[cfg(not(unix))]
+    let name = PathBuf::from(String::from_utf8_lossy(&name).into_owned());
+
+    Some((name, &s[end..]))
+}
+
+/// The file name from a `---` or `+++` line, without the timestamp after
+/// the tab that follows it. A timestamp of the epoch is diff's way of
+/// saying there's no such file, so that's /dev/null.
+fn header_name(s: &str) -> PathBuf {
+    let (name, date) = match unquote(s) {
+        Some(v) => v,
+        None => match s.split_once('\t') {
+            Some((name, date)) => (PathBuf::from(name), date),
+            None => (PathBuf::from(s), ""),
+        },
+    };
 
-    match &toy.dir {
-        Some(v) => env::set_current_dir(v)?,
-        None => {}
+    match epoch_secs(date) {
+        Some(0) => devnull().to_path_buf(),
+        _ => name,
     }
+}
 
-    let fp: Option<File> = match globals.i {
-        Some(v) => Some(File::open(v)?),
-        None => None,
+/// Seconds since the epoch of a timestamp from a diff header, either
+/// unified's "1970-01-01 00:00:00.000000000 +0000" or context's
+/// "Thu Jan  1 00:00:00 1970", where the zone is optional and UTC if it's
+/// left out. Fractions of a second don't count.
+fn epoch_secs(date: &str) -> Option<i64> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let words: Vec<&str> = date.split_whitespace().collect();
+    let num = |s: &str| s.parse::<i64>().ok();
+
+    let (year, month, day, time, zone) = match words[..] {
+        [ymd, time, ref zone @ ..] if ymd.contains('-') => {
+            let mut v = ymd.splitn(3, '-').map(num);
+            (v.next()??, v.next()??, v.next()??, time, zone.first().copied())
+        },
+        [_, mon, day, time, year, ref zone @ ..] => {
+            let month = MONTHS.iter().position(|v| *v == mon)? as i64 + 1;
+            (num(year)?, month, num(day)?, time, zone.first().copied())
+        },
+        _ => return None,
     };
 
-    let filepatch = common::Input::from(fp);
+    let mut v = time.splitn(3, ':');
+    let (h, m) = (num(v.next()?)?, num(v.next()?)?);
+    let s = num(v.next()?.split('.').next()?)?;
+
+    // +hhmm or -hhmm east of UTC.
+    let zone = match zone {
+        Some(v) if v.len() == 5 && (v.starts_with('+') || v.starts_with('-')) => {
+            let n = num(&v[1..])?;
+            let secs = (n / 100 * 60 + n % 100) * 60;
+            if v.starts_with('-') { -secs } else { secs }
+        },
+        Some(_) => return None,
+        None => 0,
+    };
+
+    // Days since the epoch of a date in the proleptic Gregorian calendar.
+    let (y, m2) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * m2 + 2) / 5 + day - 1;
+    let days = era * 146097 + yoe * 365 + yoe / 4 - yoe / 100 + doy - 719468;
+
+    Some(days * 86400 + h * 3600 + m * 60 + s - zone)
+}
 
-    for p in BufReader::new(filepatch).lines().into_iter() {
-        if let Ok(mut patchline) = p {
-            // Other versions of patch accept damaged patches, so we need to also.
-            // AMY: DOS/Windows '\r' is already handled for us.
-            if patchline.starts_with('\0') {
-                patchline = String::from(" ");
+/// Strip the first `strip` components of a path named in the patch (-p),
+/// or all but the file name when there's no -p. Returns None if that
+/// leaves nothing.
+fn strip_path(name: &Path, strip: Option<usize>) -> Option<PathBuf> {
+    match strip {
+        Some(v) => {
+            let mut n = name.components();
+            for _ in 0..v {
+                n.next()?;
+            }
+            match n.as_path() {
+                p if p.as_os_str().is_empty() => None,
+                p => Some(p.to_path_buf()),
             }
+        },
+        None => name.file_name().map(PathBuf::from),
+    }
+}
 
-            // Are we assembling a hunk?
-            if state >= 2 {
-                if patchline.starts_with(|ch| ch == ' ' || ch == '+' || ch == '-') {
-                    globals.current_hunk.push_back(patchline.to_string());
+/// The function diff -p put after the ranges in a hunk header, if any.
+fn function_name(hunkhead: &str) -> &str {
+    match hunkhead.get(4..).and_then(|v| v.split_once(" @@")) {
+        Some((_, v)) => v.trim(),
+        None => "",
+    }
+}
 
-                    if !patchline.starts_with('+') {
-                        globals.oldlen -= 1;
-                    }
+/// Apply one patch file, read from `filepatch`.
+fn apply(toy: &PatchToy, globals: &mut Globals, filepatch: common::Input) -> Result<()> {
+    let mut state: u32 = 0;
+    let _patchlinenum: isize = 0;
 
-                    if !patchline.starts_with('-') {
-                        globals.newlen -= 1;
-                    }
+    let mut oldname: Option<PathBuf> = None;
+    let mut newname: Option<PathBuf> = None;
+    let mut githead = GitHeader::default();
 
-                    // Context line?
-                    if patchline.starts_with('-') && state == 2 {
-                        globals.context += 1;
-                    } else {
+    // A git header named the file(s) being patched. git is cleared once
+    // hunks open the file; otherwise whatever the header says is done when
+    // the next file starts.
+    let mut git = false;
+
+    // A --- line whose +++ hasn't come yet.
+    let mut minus = false;
+
+    let patchlines = byte_lines(filepatch);
+
+    for mut patchline in ContextToUnified::new(patchlines) {
+        // Other versions of patch accept damaged patches, so we need to also.
+        if patchline.starts_with(b"\0") {
+            if toy.posix {
+                return Err(anyhow!("NUL byte in patch (--posix)"));
+            }
+            patchline = b" ".to_vec();
+        }
+
+        // A hunk's last line may be followed by "\ No newline at end of
+        // file", so it's only finished once the line after isn't that.
+        if state == 4 {
+            if globals.current_hunk.push(&patchline) == HunkLine::Taken {
+                continue;
+            }
+            state = globals.finish_hunk(toy)?;
+        }
+
+        // Are we assembling a hunk?
+        if state >= 2 {
+            match globals.current_hunk.push(&patchline) {
+                HunkLine::Taken => {
+                    // Past the leading context?
+                    if !matches!(patchline.first(), None | Some(b' ')) {
                         state = 3;
                     }
 
                     // If we've consumed all expected hunk lines, apply the hunk.
-                    if globals.oldlen == 0 && globals.newlen == 0 {
-                        state = globals.apply_one_hunk(&toy)?;
+                    if globals.current_hunk.is_done() {
+                        state = 4;
                     }
-                    continue;
-                }
-                globals.current_hunk.pop_front();
-                globals.fail_hunk(&toy)?;
-                state = 0;
-                continue;
+                },
+                // A hunk with more lines than its header said is corrupt.
+                HunkLine::TooLong => {
+                    eprintln!("hunk {} is longer than its header says", globals.hunknum);
+                    globals.fail_hunk(toy)?;
+                    state = 0;
+                },
+                HunkLine::Other => {
+                    globals.fail_hunk(toy)?;
+                    state = 0;
+                },
             }
+            continue;
+        }
+
+        // Everything else is headers, where a stray byte only matters to
+        // the name of a file.
+        let patchline = String::from_utf8_lossy(&patchline).into_owned();
 
-            // Open a new file?
-            if patchline.starts_with("--- ") {
-                oldname = None;
-                globals.finish_oldfile()?;
+        // A git binary patch runs until the next file.
+        if let Some(v) = githead.binary.as_mut() {
+            if !patchline.starts_with("diff --git ") {
+                v.push(patchline);
+                continue;
+            }
+        }
 
-                // Trim date from end of filename (if any).  We don't care.
-                let s: String = patchline
-                    .chars()
-                    .skip(4)
-                    .skip_while(|c| *c != '\t')
-                    .collect();
+        // A git header starts a new file, and names it so we don't have to
+        // wait for --- and +++.
+        if let Some(v) = patchline.strip_prefix("diff --git ") {
+            globals.finish_oldfile(toy)?;
+            if git {
+                globals.finish_git(toy, oldname.as_deref(), newname.as_deref(), &githead)?;
+            }
 
-                match s.parse::<usize>() {
-                    Ok(i) => {
-                        if i <= 1970 {
-                            oldname = Some(devnull());
-                        }
-                    }
-                    Err(_) => {}
+            (oldname, newname) = match git_names(v) {
+                Some((a, b)) => (Some(a), Some(b)),
+                None => (None, None),
+            };
+            git = oldname.is_some();
+            githead = GitHeader::default();
+            minus = false;
+            state = 0;
+        } else if git && patchline.starts_with("rename from ") {
+            githead.rename = true;
+        } else if git && patchline == "GIT binary patch" {
+            githead.binary = Some(Vec::new());
+        } else if git && patchline.starts_with("Binary files ") {
+            // git only says they differ unless it was asked for --binary,
+            // which leaves nothing to apply.
+            let name = newname.iter().chain(&oldname).find(|v| v.as_path() != devnull());
+            eprintln!("can't patch binary file {} (make the patch with git diff --binary)",
+                name.map(|v| v.to_string_lossy()).unwrap_or_default());
+            globals.failed += 1;
+            git = false;
+        // git says what mode a file it creates or deletes should have.
+        } else if let Some(v) = patchline.strip_prefix("new file mode ") {
+            githead.newmode = u32::from_str_radix(v.trim(), 8).ok();
+            if git {
+                oldname = Some(devnull().to_path_buf());
+            }
+        } else if let Some(v) = patchline.strip_prefix("deleted file mode ") {
+            githead.oldmode = u32::from_str_radix(v.trim(), 8).ok();
+            if git {
+                newname = Some(devnull().to_path_buf());
+            }
+        } else if let Some(v) = patchline.strip_prefix("new mode ") {
+            githead.newmode = u32::from_str_radix(v.trim(), 8).ok();
+        } else if let Some(v) = patchline.strip_prefix("old mode ") {
+            githead.oldmode = u32::from_str_radix(v.trim(), 8).ok();
+        // Open a new file?
+        } else if let Some(v) = patchline.strip_prefix("--- ") {
+            // git named the files already, but says nothing of dates.
+            let name = header_name(v);
+            if !git || name == devnull() {
+                oldname = Some(name);
+            }
+            if !git {
+                githead = GitHeader::default();
+            }
+            globals.finish_oldfile(toy)?;
+            globals.filehead = format!("{}\n", patchline);
+            minus = true;
+
+
+            // We defer actually opening the file because svn produces broken
+            // patches that don't signal they want to create a new file the
+            // way the patch man page says, so you have to read the first hunk
+            // and _guess_.
+
+            // Start a new hunk?  Usually @@ -oldline,oldlen +newline,newlen @@
+            // but a missing ,value means the value is 1.
+        } else if let Some(v) = patchline.strip_prefix("+++ ") {
+            let name = header_name(v);
+
+            // Some tools leave out the --- line, so this starts the file
+            // too, and the file is patched in place.
+            if !minus {
+                if toy.posix {
+                    return Err(anyhow!("no --- line before {} (--posix)", patchline));
+                }
+                if !toy.silent {
+                    eprintln!("no --- line before {}, patching it in place", patchline);
+                }
+                if !git {
+                    oldname = Some(name.clone()).filter(|v| v != devnull());
+                    githead = GitHeader::default();
                 }
+                globals.finish_oldfile(toy)?;
+                globals.filehead = String::new();
+            }
+            if !git || name == devnull() {
+                newname = Some(name);
+            }
+            minus = false;
+            state = 1;
 
-                // We defer actually opening the file because svn produces broken
-                // patches that don't signal they want to create a new file the
-                // way the patch man page says, so you have to read the first hunk
-                // and _guess_.
-
-                // Start a new hunk?  Usually @@ -oldline,oldlen +newline,newlen @@
-                // but a missing ,value means the value is 1.
-            } else if patchline.starts_with("+++ ") {
-                newname = None;
-                state = 1;
-
-                globals.finish_oldfile()?;
-
-                // Trim date from end of filename (if any).  We don't care.
-                let s: String = patchline
-                    .chars()
-                    .skip(4)
-                    .skip_while(|c| *c != '\t')
-                    .collect();
-
-                match s.parse::<usize>() {
-                    Ok(i) => {
-                        if i <= 1970 {
-                            newname = Some(devnull());
-                        }
-                    }
-                    Err(_) => {}
+            globals.filehead.push_str(&format!("{}\n", patchline));
+
+
+            // We defer actually opening the file because svn produces broken
+            // patches that don't signal they want to create a new file the
+            // way the patch man page says, so you have to read the first hunk
+            // and _guess_.
+
+            // Start a new hunk?  Usually @@ -oldline,oldlen +newline,newlen @@
+            // but a missing ,value means the value is 1.
+        } else if (state == 1 || minus || globals.rejecting) && patchline.starts_with("@@ -") {
+            // A hunk right after a --- line is missing its +++, so the
+            // file is patched in place.
+            if minus {
+                if toy.posix {
+                    return Err(anyhow!("no +++ line after {} (--posix)", globals.filehead.trim_end()));
                 }
+                if !toy.silent {
+                    eprintln!("no +++ line after {}, patching it in place", globals.filehead.trim_end());
+                }
+                if !git {
+                    newname = oldname.clone().filter(|v| v != devnull());
+                }
+                minus = false;
+            }
 
-                // We defer actually opening the file because svn produces broken
-                // patches that don't signal they want to create a new file the
-                // way the patch man page says, so you have to read the first hunk
-                // and _guess_.
-
-                // Start a new hunk?  Usually @@ -oldline,oldlen +newline,newlen @@
-                // but a missing ,value means the value is 1.
-            } else if state == 1 && patchline.starts_with("@@ -") {
-                let mut i: usize = 0;
-                let mut s = patchline.chars().skip(4).peekable();
-
-                // Read oldline[,oldlen] +newline[,newlen]
-
-                globals.oldlen = 1;
-                globals.newlen = 1;
-
-                {
-                    let x: String = s
-                        .by_ref()
-                        .skip_while(|c| c.is_ascii_whitespace())
-                        .peekable()
-                        .peeking_take_while(|c| c.is_ascii_digit())
-                        .collect();
-                    globals.oldline = x.parse::<usize>()?;
-                    if s.by_ref().peek() == Some(&',') {
-                        s.by_ref().next();
-                        let x: String = s
-                            .by_ref()
-                            .skip_while(|c| c.is_ascii_whitespace())
-                            .peekable()
-                            .peeking_take_while(|c| c.is_ascii_digit())
-                            .collect();
-                        globals.oldlen = x.parse::<usize>()?;
-                    }
+            // Read oldline[,oldlen] +newline[,newlen]
+            let [(oldline, oldlen), (newline, newlen)] = hunk_range(patchline.as_bytes())
+                .ok_or_else(|| anyhow!("bad hunk header: {}", patchline.trim_end()))?;
+            (globals.oldline, globals.newline) = (oldline, newline);
+            globals.current_hunk = HunkReader::new(oldlen, newlen);
+
+            globals.hunkhead = patchline.clone();
+            state = 2;
+
+            // If this is the first hunk, open the file.
+            if globals.file.is_none() && !globals.rejecting {
+                let mut del: usize = 0;
+                let mut name: PathBuf;
+
+                let mut strip = toy.strip;
+
+                // svn makes patches that create or remove files without
+                // saying /dev/null, so a side that's empty does too, unless
+                // --posix.
+                let oldsum = globals.oldline + oldlen;
+                let newsum = globals.newline + newlen;
+
+                // If an original file was provided on the command line, it overrides
+                // *all* files mentioned in the patch, not just the first, whether
+                // they're being created, deleted or modified.
+                let target = toy.files.first().cloned();
+                if target.is_some() {
+                    // The supplied path should be taken literally with or without -p,
+                    // and is patched where it is even if the patch renames it.
+                    strip = Some(0);
+                    githead.rename = false;
                 }
 
-                s.by_ref().next().ok_or_else(|| anyhow!("Missing data?"))?;
-                s.by_ref().next().ok_or_else(|| anyhow!("Missing data?"))?;
-
-                {
-                    let x: String = s
-                        .by_ref()
-                        .skip_while(|c| c.is_ascii_whitespace())
-                        .peekable()
-                        .peeking_take_while(|c| c.is_ascii_digit())
-                        .collect();
-                    globals.newline = x.parse::<usize>()?;
-
-                    if s.by_ref().peek() == Some(&',') {
-                        s.by_ref().next();
-                        let x: String = s
-                            .by_ref()
-                            .skip_while(|c| c.is_ascii_whitespace())
-                            .peekable()
-                            .peeking_take_while(|c| c.is_ascii_digit())
-                            .collect();
-                        globals.newlen = x.parse::<usize>()?;
+                // A renamed file is read from its other name.
+                let mut from: Option<PathBuf> = None;
+
+                if toy.reverse {
+                    name = oldname
+                        .clone()
+                        .ok_or_else(|| anyhow!("Undefined old file"))?;
+
+                    // We're deleting oldname if new file is /dev/null (before -p)
+                    // or if new hunk is empty (zero context) after patching
+                    if oldname.as_deref() == Some(devnull()) || (oldsum == 0 && !toy.posix) {
+                        name = newname
+                            .clone()
+                            .ok_or_else(|| anyhow!("Undefined old file for removal"))?;
+                        del += 1;
+                    } else if githead.rename {
+                        from = newname.clone();
+                    }
+                } else {
+                    name = newname
+                        .clone()
+                        .ok_or_else(|| anyhow!("Undefined new file"))?;
+
+                    if newname.as_deref() == Some(devnull()) || (newsum == 0 && !toy.posix) {
+                        name = oldname
+                            .clone()
+                            .ok_or_else(|| anyhow!("Undefined new file for removal"))?;
+                        del += 1;
+                    } else if githead.rename {
+                        from = oldname.clone();
                     }
                 }
+                if let Some(v) = target {
+                    name = v;
+                }
+                git = false;
+
+                // handle -p path truncation.
+                let from = from.and_then(|v| strip_path(&v, strip));
+                let name = match strip_path(&name, strip) {
+                    Some(v) => v,
+                    None => {
+                        eprintln!("can't strip {} leading components from {}",
+                            strip.unwrap_or_default(), name.to_string_lossy());
+                        globals.failed += 1;
+                        state = 0;
+                        continue;
+                    }
+                };
 
-                globals.context = 0;
-                state = 2;
-
-                // If this is the first hunk, open the file.
-                if globals.filein.is_none() {
-                    let mut del: usize = 0;
-                    let mut name: PathBuf = PathBuf::new();
-
-                    let oldsum = globals.oldline + globals.oldlen;
-                    let newsum = globals.newline + globals.newlen;
+                if let (Some(v), true) = (&toy.output, globals.patched > 0) {
+                    eprintln!("can't write more than one patched file to {}", v.to_string_lossy());
+                    globals.failed += 1;
+                    state = 0;
+                    continue;
+                }
+                globals.patched += 1;
 
-                    // If an original file was provided on the command line, it overrides
-                    // *all* files mentioned in the patch, not just the first.
-                    if !toy.files.is_empty() {
-                        if _reverse {
-                            oldname = Some(toy.files[0].as_path());
-                        } else {
-                            newname = Some(toy.files[0].as_path());
-                        }
+                if del > 0 {
+                    if !toy.silent {
+                        println!("removing {}", name.to_string_lossy());
+                    }
 
-                        // The supplied path should be taken literally with or without -p.
-                        toy.strip = None;
+                    // With -o, what's left of the file is nothing.
+                    match &toy.output {
+                        _ if toy.dry_run => (),
+                        Some(v) => {
+                            File::create(v)?;
+                        },
+                        None => {
+                            if let Err(e) = std::fs::remove_file(&name) {
+                                eprintln!("can't remove {}: {}", name.to_string_lossy(), e);
+                                globals.failed += 1;
+                            }
+                        },
                     }
 
-                    if toy.reverse {
-                        // oldname
-                        // We're deleting oldname if new file is /dev/null (before -p)
-                        // or if new hunk is empty (zero context) after patching
-                        if oldname == Some(devnull()) || oldsum > 0 {
-                            name = newname
-                                .ok_or_else(|| anyhow!("Undefined old file for removal"))?
-                                .to_path_buf();
-                            del += 1;
+                    state = 0;
+                // If we've got a file to open, do so.
+                } else {
+                    // If the old file was null, we're creating a new one.
+                    let created = match toy.reverse {
+                        true => newname.as_deref() == Some(devnull()) || (newsum == 0 && !toy.posix),
+                        false => oldname.as_deref() == Some(devnull()) || (oldsum == 0 && !toy.posix),
+                    };
+                    let filein = if created && !name.exists() {
+                        if !toy.silent {
+                            println!("creating {}", name.to_string_lossy());
                         }
 
-                        // handle -p path truncation.
-                        match toy.strip {
-                            Some(v) => {
-                                let mut n = name.components();
-                                let mut s: Option<&Path> = None;
-                                loop {
-                                    // XX n.skip(v) moves
-                                    match n.next() {
-                                        Some(_) => {
-                                            if i == v {
-                                                break;
-                                            }
-                                            s = Some(n.as_path());
-                                            i += 1;
-                                            continue;
-                                        }
-                                        None => {
-                                            break;
-                                        }
-                                    }
+                        globals.mode = githead.mode(toy.reverse);
+
+                        // A new file is patched from nothing, and only comes
+                        // to exist when its temp file is renamed into place,
+                        // along with any directories it needs.
+                        File::open(devnull())?
+                    } else {
+                        let src = match &from {
+                            Some(v) if *v != name => {
+                                if !toy.silent {
+                                    println!("patching {} (renamed from {})",
+                                        name.to_string_lossy(), v.to_string_lossy());
                                 }
-                                name = s.unwrap().to_path_buf();
-                            }
-                            None => {}
+                                v.clone()
+                            },
+                            _ => {
+                                if !toy.silent {
+                                    println!("patching {}", name.to_string_lossy());
+                                }
+                                name.clone()
+                            },
+                        };
+                        // One file missing is no reason to give up on the rest.
+                        let mut filein = match File::open(&src) {
+                            Ok(v) => v,
+                            Err(e) => {
+                                eprintln!("can't open {}: {}", src.to_string_lossy(), e);
+                                globals.failed += 1;
+                                state = 0;
+                                continue;
+                            },
+                        };
+                        if !toy.text && looks_binary(&mut filein)? {
+                            eprintln!("{} looks binary, skipping it (-a patches it anyway)",
+                                src.to_string_lossy());
+                            globals.failed += 1;
+                            state = 0;
+                            continue;
                         }
+                        globals.mode = githead.mode(toy.reverse);
+                        globals.renamed = Some(src.clone()).filter(|v| *v != name);
+                        filein
+                    };
+                    let fileout = if toy.dry_run {
+                        globals.rejname = None;
+                        globals.destname = Some(toy.output.clone().unwrap_or_else(|| name.clone()));
+                        OpenOptions::new().read(true).write(true).open(devnull())?
                     } else {
-                        // newname
-                        if newname == Some(devnull()) || newsum > 0 {
-                            name = oldname
-                                .ok_or_else(|| anyhow!("Undefined new file for removal"))?
-                                .to_path_buf();
-                            del += 1;
-                        }
-
-                        // handle -p path truncation.
-                        match toy.strip {
-                            Some(v) => {
-                                let mut n = name.components();
-                                let mut s: Option<&Path> = None;
-                                loop {
-                                    // XX n.skip(v) moves
-                                    match n.next() {
-                                        Some(_) => {
-                                            if i == v {
-                                                break;
-                                            }
-                                            s = Some(n.as_path());
-                                            i += 1;
-                                            continue;
-                                        }
-                                        None => {
-                                            break;
-                                        }
-                                    }
-                                }
-                                name = s.unwrap().to_path_buf();
+                        globals.rejname = match &toy.reject_file {
+                            Some(v) => Some(v.clone()),
+                            None => {
+                                let mut rej = name.as_os_str().to_owned();
+                                rej.push(".rej");
+                                Some(PathBuf::from(rej))
                             }
-                            None => {}
-                        }
-                    }
+                        };
+                        // A renamed file is read from its old name and written
+                        // to its new one, and -o leaves the original where it is.
+                        let src = globals.renamed.clone().unwrap_or_else(|| name.clone());
+                        let dest = match &toy.output {
+                            Some(v) => {
+                                globals.renamed = None;
+                                v.clone()
+                            },
+                            None => name,
+                        };
+                        make_parents(&dest)?;
+                        let x = copy_tempfile(&src, &dest)?;
+                        globals.tempname = Some(x.0);
+                        globals.destname = Some(dest);
+                        x.1
+                    };
+                    globals.file = Some(Patcher::new(BufReader::new(filein), fileout));
+                    githead = GitHeader::default();
+                }
+            }
 
-                    if del > 0 {
-                        if !toy.silent {
-                            println!("removing {}", name.to_string_lossy());
-                        }
+            globals.hunknum += 1;
+        }
 
-                        std::fs::remove_file(name)?;
+        // If we didn't continue above, discard this line.
+    }
 
-                        state = 0;
-                    // If we've got a file to open, do so.
-                    } else if toy.strip.is_none() || i <= toy.strip.unwrap_or_default() {
-                        // If the old file was null, we're creating a new one.
-                        if (oldname == Some(devnull()) || oldsum == 0) && name.exists() {
-                            if !toy.silent {
-                                println!("creating {}", name.to_string_lossy());
-                            }
+    if state == 4 {
+        globals.finish_hunk(toy)?;
+    }
+    globals.finish_oldfile(toy)?;
+    if git {
+        globals.finish_git(toy, oldname.as_deref(), newname.as_deref(), &githead)?;
+    }
 
-                            let mkpath = name
-                                .parent()
-                                .ok_or_else(|| anyhow!("Unknown parent folder for new file"))?;
+    Ok(())
+}
 
-                            std::fs::create_dir_all(mkpath)?;
+fn patch_main() -> Result<()> {
+    let toy: PatchToy = PatchToy::from_args();
 
-                            globals.filein = Some(File::create(&name)?);
-                        } else {
-                            if !toy.silent {
-                                println!("patching {}", name.to_string_lossy());
-                            }
-                            globals.filein = Some(File::open(&name)?);
-                        }
-                        if toy.dry_run {
-                            globals.fileout =
-                                Some(OpenOptions::new().read(true).write(true).open(devnull())?);
-                        } else {
-                            let x = copy_tempfile(&name)?;
-                            globals.tempname = Some(x.0);
-                            globals.fileout = Some(x.1);
-                        }
-                        globals.linenum = 0;
-                        globals.outnum = 0;
-                        globals.hunknum = 0;
-                    }
-                }
-            }
+    let mut globals: Globals = Default::default();
 
-            globals.hunknum += 1;
+    if toy.files.len() == 2 {
+        globals.i = Some(&toy.files[1]);
+    }
 
-            continue;
+    // -s wins over -v: nothing but errors.
+    if toy.verbose && !toy.silent {
+        log::set_logger(&VERBOSE).map_err(|e| anyhow!("{}", e))?;
+        log::set_max_level(log::LevelFilter::Debug);
+    }
+
+    if let Some(v) = &toy.dir {
+        env::set_current_dir(v)?;
+    }
+
+    // Patch files named by -i follow the one on the command line, or stdin
+    // if there are none.
+    let mut inputs: Vec<Option<&PathBuf>> =
+        globals.i.iter().copied().chain(&toy.input).map(Some).collect();
+    if inputs.is_empty() {
+        inputs.push(None);
+    }
+
+    // They're read as one long patch, unless we need to know where one
+    // ends to stop after it.
+    let inputs: Vec<Vec<Option<&PathBuf>>> = match toy.stop_on_error {
+        true => inputs.into_iter().map(|v| vec![v]).collect(),
+        false => vec![inputs],
+    };
+
+    for i in inputs {
+        let fp = i.into_iter()
+            .map(|v| v.map(File::open).transpose())
+            .collect::<io::Result<common::Input>>()?;
+
+        apply(&toy, &mut globals, fp)?;
+
+        if toy.stop_on_error && globals.failed > 0 {
+            break;
+        }
+    }
+
+    // Once there's more than one file, say which of them didn't go in.
+    let failed: Vec<_> = globals.results.iter().filter(|v| v.2 > 0).collect();
+    if globals.results.len() > 1 && !failed.is_empty() {
+        eprintln!("{} of {} files had hunks that FAILED:", failed.len(), globals.results.len());
+        for (name, hunks, rejects) in failed {
+            let what = match toy.force || toy.dry_run {
+                true => "",
+                false => ", file left unchanged",
+            };
+            eprintln!("  {}: {} of {} hunks FAILED{}", name.to_string_lossy(), rejects, hunks, what);
         }
-        // If we didn't continue above, discard this line.
     }
 
-    globals.finish_oldfile()?;
+    if toy.dry_run && !toy.silent {
+        println!("{} hunk{} would apply, {} would FAIL", globals.applied,
+            if globals.applied == 1 { "" } else { "s" }, globals.failed);
+    }
+
+    match globals.failed {
+        0 => Ok(()),
+        count => Err(PatchError::Failed { count }.into()),
+    }
+}
+
+/// Exit 1 if some of the patch didn't apply, and 2 if something else went
+/// wrong.
+fn main() {
+    if let Err(e) = patch_main() {
+        if e.downcast_ref::<PatchError>().is_none() {
+            eprintln!("patch: {:#}", e);
+            process::exit(2);
+        }
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // git diff --binary of a 12-byte file, and the way back to 11 bytes.
+    const LITERAL: &[&str] = &[
+        "literal 12",
+        "TcmZQzWODNKa}0|7$G`;u4sQa{",
+        "",
+        "literal 11",
+        "ScmZQzWJ=1+ODwAV&jkPv$^()B",
+        "",
+    ];
+
+    #[test]
+    fn strip() {
+        let cases: &[(&str, Option<usize>, Option<&str>)] = &[
+            ("a/b/c.txt", Some(0), Some("a/b/c.txt")),
+            ("a/b/c.txt", Some(1), Some("b/c.txt")),
+            ("a/b/c.txt", Some(2), Some("c.txt")),
+            ("a/b/c.txt", Some(3), None),
+            ("a/b/c.txt", Some(9), None),
+            ("a/b/c.txt", None, Some("c.txt")),
+            ("c.txt", None, Some("c.txt")),
+            ("/abs/c.txt", Some(1), Some("abs/c.txt")),
+            // git's prefixes are a component like any other.
+            ("a/src/lib/mod.rs", Some(1), Some("src/lib/mod.rs")),
+            ("b/src/lib/mod.rs", Some(2), Some("lib/mod.rs")),
+        ];
+
+        for &(name, strip, want) in cases {
+            assert_eq!(strip_path(Path::new(name), strip), want.map(PathBuf::from), "{} -p{:?}", name, strip);
+        }
+    }
+
+    #[test]
+    fn epoch() {
+        assert_eq!(epoch_secs("1970-01-01 00:00:00.000000000 +0000"), Some(0));
+        assert_eq!(epoch_secs("1970-01-01 00:00:00"), Some(0));
+        assert_eq!(epoch_secs("1970-01-01 01:00:00.000000000 +0100"), Some(0));
+        assert_eq!(epoch_secs("1969-12-31 16:00:00 -0800"), Some(0));
+        assert_eq!(epoch_secs("Thu Jan  1 00:00:00 1970"), Some(0));
+        assert_eq!(epoch_secs("2023-01-01 12:00:00.000000000 +0000"), Some(1672574400));
+        assert_eq!(epoch_secs("Sun Jan  1 12:00:00 2023"), Some(1672574400));
+        assert_eq!(epoch_secs(""), None);
+        assert_eq!(epoch_secs("yesterday"), None);
+    }
+
+    #[test]
+    fn header_names() {
+        assert_eq!(header_name("/dev/null"), devnull());
+        assert_eq!(header_name("a/f\t1970-01-01 00:00:00.000000000 +0000"), devnull());
+        assert_eq!(header_name("a/f\tThu Jan  1 00:00:00 1970"), devnull());
+        assert_eq!(header_name("a/f\t2023-01-01 12:00:00.000000000 +0000"), Path::new("a/f"));
+        assert_eq!(header_name("a/f"), Path::new("a/f"));
+    }
+
+    #[test]
+    fn base85() {
+        // 'T' says 20 bytes follow: the deflated 12.
+        let v = decode_base85(LITERAL[1]).unwrap();
+        assert_eq!(v.len(), 20);
+        assert_eq!(&v[..2], b"\x78\x01");
+
+        assert_eq!(decode_base85("A"), None);
+        assert_eq!(decode_base85("B\x7f\x7f\x7f\x7f\x7f"), None);
+    }
+
+    #[test]
+    fn binary_literal() {
+        let lines = LITERAL.iter().map(|v| v.to_string()).collect::<Vec<_>>();
+
+        let new = binary_patch(&lines, false, devnull()).unwrap();
+        assert_eq!(new, b"\x00\x01\x02BINARY\xfe\x00\n");
 
-    match globals.exitval {
-        Some(v) => Err(anyhow!(v)),
-        None => Ok(()),
+        let old = binary_patch(&lines, true, devnull()).unwrap();
+        assert_eq!(old, b"\x00\x01\x02binary\xff\n");
     }
 }
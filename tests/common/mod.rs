@@ -0,0 +1,86 @@
+//! Running the diff and patch binaries on files in a scratch directory.
+
+#![allow(dead_code)]
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+/// A directory of its own for a test, gone once the test's done with it.
+pub struct Scratch {
+    pub path: PathBuf,
+}
+
+impl Scratch {
+    /// Tests run in parallel, so each one names its own.
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("toyrust-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(&path).unwrap();
+
+        Scratch { path }
+    }
+
+    /// Write a file, and any directories it needs.
+    pub fn write(&self, name: &str, contents: impl AsRef<[u8]>) {
+        let path = self.path.join(name);
+        if let Some(v) = path.parent() {
+            fs::create_dir_all(v).unwrap();
+        }
+        fs::write(path, contents).unwrap();
+    }
+
+    pub fn mkdir(&self, name: &str) {
+        fs::create_dir_all(self.path.join(name)).unwrap();
+    }
+
+    pub fn read(&self, name: &str) -> String {
+        String::from_utf8(self.read_bytes(name)).unwrap()
+    }
+
+    pub fn read_bytes(&self, name: &str) -> Vec<u8> {
+        fs::read(self.path.join(name)).unwrap()
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.path.join(name).exists()
+    }
+
+    pub fn diff(&self, args: &[&str]) -> Output {
+        self.run(env!("CARGO_BIN_EXE_diff"), args, b"")
+    }
+
+    pub fn patch(&self, args: &[&str], stdin: impl AsRef<[u8]>) -> Output {
+        self.run(env!("CARGO_BIN_EXE_patch"), args, stdin.as_ref())
+    }
+
+    fn run(&self, bin: impl AsRef<Path>, args: &[&str], stdin: &[u8]) -> Output {
+        let mut child = Command::new(bin.as_ref())
+            .args(args)
+            .current_dir(&self.path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(stdin).unwrap();
+
+        child.wait_with_output().unwrap()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+pub fn stdout(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stdout).into_owned()
+}
+
+pub fn stderr(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stderr).into_owned()
+}
@@ -0,0 +1,351 @@
+//! The diff binary, run on files in a scratch directory.
+
+mod common;
+
+use common::{stderr, stdout, Scratch};
+
+/// The ---/+++ lines of a unified diff, without their dates.
+fn headers(out: &str) -> Vec<&str> {
+    out.lines()
+        .filter(|v| v.starts_with("--- ") || v.starts_with("+++ "))
+        .map(|v| v.split('\t').next().unwrap_or(v))
+        .collect()
+}
+
+#[test]
+fn jobs_keep_path_order() {
+    let t = Scratch::new("jobs_keep_path_order");
+    for i in 0..20 {
+        t.write(&format!("a/f{:02}", i), format!("{}\nsame\n", i));
+        t.write(&format!("b/f{:02}", i), format!("{}\nsame\n", i + i % 3));
+    }
+    t.write("a/only_a", "x\n");
+    t.write("a/sub/only_a", "x\n");
+    t.write("b/only_b", "y\n");
+    t.write("b/zz", "y\n");
+
+    let serial = t.diff(&["-r", "a", "b"]);
+    let parallel = t.diff(&["-r", "-j4", "a", "b"]);
+
+    assert_eq!(parallel.status.code(), Some(1));
+    assert_eq!(stderr(&parallel), "");
+    assert_eq!(stdout(&parallel), stdout(&serial));
+    assert!(stdout(&parallel).contains("Only in a: only_a\n"));
+    assert!(stdout(&parallel).contains("Only in b: zz\n"));
+
+    // With -N, the files only on one side are compared too.
+    let serial = t.diff(&["-rN", "a", "b"]);
+    let parallel = t.diff(&["-rN", "-j4", "a", "b"]);
+    assert_eq!(stderr(&parallel), "");
+    assert_eq!(stdout(&parallel), stdout(&serial));
+}
+
+#[cfg(unix)]
+#[test]
+fn directory_loop_is_trouble() {
+    use std::os::unix::fs::symlink;
+
+    let t = Scratch::new("directory_loop_is_trouble");
+    t.write("a/f", "x\n");
+    t.write("b/f", "x\n");
+    symlink(".", t.path.join("a/loop")).unwrap();
+    symlink(".", t.path.join("b/loop")).unwrap();
+
+    let out = t.diff(&["-r", "a", "b"]);
+    assert_eq!(out.status.code(), Some(2));
+    assert!(stderr(&out).contains("recursive directory loop"), "{}", stderr(&out));
+
+    // Without following links, they're just the same link.
+    let out = t.diff(&["-r", "--no-dereference", "a", "b"]);
+    assert_eq!(out.status.code(), Some(0));
+}
+
+#[cfg(unix)]
+#[test]
+fn fifo_is_not_read() {
+    use std::process::Command;
+
+    let t = Scratch::new("fifo_is_not_read");
+    t.write("f", "x\n");
+    assert!(Command::new("mkfifo").arg(t.path.join("p")).status().unwrap().success());
+
+    // Nothing writes to the pipe, so reading it would hang.
+    let out = t.diff(&["p", "f"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(stdout(&out), "File p is a fifo while file f is a regular file\n");
+
+    let out = t.diff(&["f", "p"]);
+    assert_eq!(stdout(&out), "File f is a regular file while file p is a fifo\n");
+
+    // /dev/null is a device, but reads as empty.
+    let out = t.diff(&["f", "/dev/null"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(stdout(&out).ends_with("@@ -1 +0,0 @@\n-x\n"));
+
+    // Nor is one met in a directory walk.
+    t.mkdir("a");
+    t.write("b/p", "x\n");
+    assert!(Command::new("mkfifo").arg(t.path.join("a/p")).status().unwrap().success());
+    let out = t.diff(&["-r", "a", "b"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(stdout(&out), "File a/p is a fifo while file b/p is a regular file\n");
+}
+
+#[test]
+fn ignore_matching_lines() {
+    let t = Scratch::new("ignore_matching_lines");
+    t.write("a", "one\n$Id: 1 $\ntwo\n");
+    t.write("b", "one\n$Id: 2 $\ntwo\n");
+    t.write("c", "one\n$Id: 2 $\nthree\n");
+
+    let out = t.diff(&["-I", r"\$Id", "a", "b"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "");
+
+    let out = t.diff(&["-I", r"\$Id", "a", "c"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(stdout(&out).contains("-$Id: 1 $\n-two\n+$Id: 2 $\n+three\n"), "{}", stdout(&out));
+}
+
+#[test]
+fn strip_trailing_cr() {
+    let t = Scratch::new("strip_trailing_cr");
+    t.write("crlf", "a\r\nb\r\n");
+    t.write("lf", "a\nb\n");
+
+    // Without it, every line differs by its '\r'.
+    let out = t.diff(&["crlf", "lf"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(stdout(&out).ends_with("-a\r\n-b\r\n+a\n+b\n"));
+
+    let out = t.diff(&["--strip-trailing-cr", "crlf", "lf"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "");
+}
+
+#[test]
+fn brief_line_endings() {
+    use std::process::Command;
+
+    let t = Scratch::new("brief_line_endings");
+    t.write("crlf", "a\r\nb\r\n");
+    t.write("lf", "a\nb\n");
+
+    let out = t.diff(&["-q", "crlf", "lf"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(stdout(&out), "Files crlf and lf differ (lines end in CRLF and LF)\n");
+
+    // A pipe can only be read once, so the note comes from the comparison.
+    let out = Command::new("bash")
+        .arg("-c")
+        .arg(format!("{} -q <(cat crlf) lf", env!("CARGO_BIN_EXE_diff")))
+        .current_dir(&t.path)
+        .output()
+        .unwrap();
+    assert_eq!(out.status.code(), Some(1));
+    assert!(stdout(&out).ends_with(" and lf differ (lines end in CRLF and LF)\n"), "{}", stdout(&out));
+}
+
+#[test]
+fn crlf_side_by_side() {
+    let t = Scratch::new("crlf_side_by_side");
+    t.write("crlf", "a\r\n");
+    t.write("lf", "a\n");
+
+    let out = t.diff(&["--crlf", "crlf", "lf"]);
+    assert_eq!(out.status.code(), Some(0));
+
+    // The columns can't hold a line ending, so it's a usage error.
+    let out = t.diff(&["--crlf", "-y", "crlf", "lf"]);
+    assert_eq!(out.status.code(), Some(2));
+    assert_eq!(stdout(&out), "");
+    assert!(stderr(&out).contains("'--crlf' cannot be used with '-y'"), "{}", stderr(&out));
+}
+
+#[test]
+fn report_identical_files() {
+    let t = Scratch::new("report_identical_files");
+    t.write("f", "x\n");
+    t.write("g", "x\n");
+
+    // The same file, and a copy of it.
+    let out = t.diff(&["-s", "f", "f"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "Files f and f are identical\n");
+
+    let out = t.diff(&["-s", "f", "g"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "Files f and g are identical\n");
+
+    // Each pair in a directory.
+    t.write("a/same", "x\n");
+    t.write("b/same", "x\n");
+    t.write("a/sub/same", "y\n");
+    t.write("b/sub/same", "y\n");
+    let out = t.diff(&["-rs", "a", "b"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(stdout(&out), "Files a/same and b/same are identical\nFiles a/sub/same and b/sub/same are identical\n");
+}
+
+#[test]
+fn new_file_in_directories() {
+    let t = Scratch::new("new_file_in_directories");
+    t.write("a/both", "x\n");
+    t.write("b/both", "y\n");
+    t.write("a/left", "l\n");
+    t.write("b/right", "r\n");
+
+    // Files on both sides, only on the left, and only on the right.
+    let out = t.diff(&["-rN", "a", "b"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(stderr(&out), "");
+    assert_eq!(headers(&stdout(&out)), [
+        "--- a/both", "+++ b/both",
+        "--- a/left", "+++ b/left",
+        "--- a/right", "+++ b/right",
+    ]);
+    assert!(stdout(&out).contains("@@ -1 +0,0 @@\n-l\n"));
+    assert!(stdout(&out).contains("@@ -0,0 +1 @@\n+r\n"));
+
+    // Without -r a directory on one side only isn't a common one.
+    t.write("na/sub/f", "x\n");
+    t.mkdir("nb");
+    let out = t.diff(&["-N", "na", "nb"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(stderr(&out), "");
+    assert_eq!(stdout(&out), "Only in na: sub\n");
+}
+
+#[test]
+fn only_in() {
+    let t = Scratch::new("only_in");
+    for v in ["a/1", "a/3", "a/4", "a/6", "b/2", "b/3", "b/5", "b/6", "b/7"] {
+        t.write(v, "x\n");
+    }
+    t.write("a/dir/f", "x\n");
+
+    let out = t.diff(&["-r", "a", "b"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(stdout(&out), "\
+Only in a: 1
+Only in b: 2
+Only in a: 4
+Only in b: 5
+Only in b: 7
+Only in a: dir
+");
+}
+
+#[test]
+fn different_root_names() {
+    let t = Scratch::new("different_root_names");
+    t.write("old/f", "x\n");
+    t.write("old/sub/g", "x\n");
+    t.write("new/tree/f", "y\n");
+    t.write("new/tree/sub/g", "x\n");
+    t.write("new/tree/sub/h", "x\n");
+
+    // Relative paths pair up although the roots differ in name and depth.
+    let out = t.diff(&["-r", "old", "new/tree"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(headers(&stdout(&out)), ["--- old/f", "+++ new/tree/f"]);
+    assert!(stdout(&out).ends_with("Only in new/tree/sub: h\n"));
+}
+
+#[test]
+fn exit_status() {
+    let t = Scratch::new("exit_status");
+    t.write("a", "x\n");
+    t.write("b", "x\n");
+    t.write("c", "y\n");
+    t.write("d1/f", "x\n");
+    t.write("d2/f", "x\n");
+    t.write("d3/f", "y\n");
+
+    let code = |args: &[&str]| t.diff(args).status.code();
+    for flags in [&[][..], &["-q"], &["-s"]] {
+        let with = |files: &[&'static str]| [flags, files].concat();
+        assert_eq!(code(&with(&["a", "b"])), Some(0), "{:?}", flags);
+        assert_eq!(code(&with(&["a", "c"])), Some(1), "{:?}", flags);
+        assert_eq!(code(&with(&["a", "missing"])), Some(2), "{:?}", flags);
+        assert_eq!(code(&with(&["-r", "d1", "d2"])), Some(0), "{:?}", flags);
+        assert_eq!(code(&with(&["-r", "d1", "d3"])), Some(1), "{:?}", flags);
+    }
+
+    let out = t.diff(&["a", "missing"]);
+    assert_eq!(stderr(&out), "diff: missing: No such file or directory\n");
+}
+
+#[test]
+fn same_file() {
+    let t = Scratch::new("same_file");
+    t.write("f", "x\n");
+    std::fs::hard_link(t.path.join("f"), t.path.join("link")).unwrap();
+    #[cfg(unix)]
+    std::os::unix::fs::symlink("f", t.path.join("sym")).unwrap();
+
+    // The same file under other names is identical to itself.
+    let mut names = vec!["./f", "link"];
+    if cfg!(unix) {
+        names.push("sym");
+    }
+    for v in names {
+        let out = t.diff(&["-s", "f", v]);
+        assert_eq!(out.status.code(), Some(0));
+        assert_eq!(stdout(&out), format!("Files f and {} are identical\n", v));
+    }
+}
+
+#[test]
+fn tabsize() {
+    let t = Scratch::new("tabsize");
+    t.write("a", "\tx\nab\tc\n");
+    t.write("b", "\ty\nab\tc\n");
+
+    // Tab stops count from after the gutter.
+    let out = t.diff(&["-t", "--tabsize=4", "a", "b"]);
+    assert!(stdout(&out).ends_with("-    x\n+    y\n ab  c\n"), "{}", stdout(&out));
+
+    let out = t.diff(&["-t", "a", "b"]);
+    assert!(stdout(&out).ends_with("-        x\n+        y\n ab      c\n"), "{}", stdout(&out));
+
+    let out = t.diff(&["--tabsize=0", "a", "b"]);
+    assert_eq!(out.status.code(), Some(2));
+}
+
+#[test]
+fn file_and_directory() {
+    let t = Scratch::new("file_and_directory");
+    t.write("dir/g", "x\n");
+    t.write("sub/g", "y\n");
+
+    // The file is compared with the one of the same name in the directory.
+    let out = t.diff(&["dir", "sub/g"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(headers(&stdout(&out)), ["--- dir/g", "+++ sub/g"]);
+
+    let out = t.diff(&["sub/g", "dir"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(headers(&stdout(&out)), ["--- sub/g", "+++ dir/g"]);
+
+    let out = t.diff(&["sub/g", "sub"]);
+    assert_eq!(out.status.code(), Some(0));
+}
+
+#[test]
+fn ignore_file_name_case() {
+    let t = Scratch::new("ignore_file_name_case");
+    t.write("a/README", "x\n");
+    t.write("a/Sub/f", "x\n");
+    t.write("b/readme", "x\n");
+    t.write("b/sub/f", "y\n");
+
+    let out = t.diff(&["-r", "a", "b"]);
+    assert_eq!(stdout(&out), "Only in a: README\nOnly in a: Sub\nOnly in b: readme\nOnly in b: sub\n");
+
+    // Paired up whatever their case, including directories.
+    let out = t.diff(&["-r", "--ignore-file-name-case", "a", "b"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(headers(&stdout(&out)), ["--- a/Sub/f", "+++ b/sub/f"]);
+    assert!(!stdout(&out).contains("Only in"));
+}
@@ -0,0 +1,252 @@
+//! The patch binary, run on files in a scratch directory.
+
+mod common;
+
+use common::{stderr, stdout, Scratch};
+
+#[test]
+fn rejected_hunk() {
+    let t = Scratch::new("rejected_hunk");
+    t.write("f", "one\ntwo\nthree\n");
+    let patch = "--- f\n+++ f\n@@ -1,3 +1,3 @@\n one\n-zwei\n+TWO\n three\n";
+
+    // The hunk goes to the reject file as it was in the patch, and the
+    // file is left alone.
+    let out = t.patch(&["f"], patch);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(stderr(&out).contains("Hunk 1 FAILED 1/1."), "{}", stderr(&out));
+    assert_eq!(t.read("f"), "one\ntwo\nthree\n");
+    assert_eq!(t.read("f.rej"), patch);
+    assert!(stdout(&out).contains("patching f\n"));
+}
+
+#[test]
+fn git_rename() {
+    let t = Scratch::new("git_rename");
+    t.write("old.txt", "one\ntwo\nthree\n");
+    let patch = "\
+diff --git a/old.txt b/new.txt
+similarity index 60%
+rename from old.txt
+rename to new.txt
+index 4cb29ea..5e2a4b3 100644
+--- a/old.txt
++++ b/new.txt
+@@ -1,3 +1,3 @@
+ one
+-two
++TWO
+ three
+";
+
+    let out = t.patch(&["-p1"], patch);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    assert_eq!(stdout(&out), "patching new.txt (renamed from old.txt)\n");
+    assert!(!t.exists("old.txt"));
+    assert_eq!(t.read("new.txt"), "one\nTWO\nthree\n");
+}
+
+#[test]
+fn git_prefixes() {
+    let t = Scratch::new("git_prefixes");
+    t.write("src/lib/mod.rs", "fn a() {}\n");
+    let patch = "\
+diff --git a/src/lib/mod.rs b/src/lib/mod.rs
+--- a/src/lib/mod.rs
++++ b/src/lib/mod.rs
+@@ -1 +1 @@
+-fn a() {}
++fn b() {}
+";
+
+    // -p1 takes off just the a/ and b/.
+    let out = t.patch(&["-p1"], patch);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    assert_eq!(stdout(&out), "patching src/lib/mod.rs\n");
+    assert_eq!(t.read("src/lib/mod.rs"), "fn b() {}\n");
+
+    // -p2 takes off src/ too, so it's found from inside src.
+    let out = t.patch(&["-R", "-p2", "-d", "src"], patch);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    assert_eq!(stdout(&out), "patching lib/mod.rs\n");
+    assert_eq!(t.read("src/lib/mod.rs"), "fn a() {}\n");
+}
+
+#[test]
+fn hunk_longer_than_header() {
+    let t = Scratch::new("hunk_longer_than_header");
+    t.write("f", "one\ntwo\nthree\n");
+
+    // The old side runs out while the new one still has a line to come.
+    let out = t.patch(&[], "--- f\n+++ f\n@@ -1,2 +1,3 @@\n one\n two\n-three\n+THREE\n");
+    assert_eq!(out.status.code(), Some(1));
+    assert!(stderr(&out).contains("hunk 1 is longer than its header says\n"), "{}", stderr(&out));
+    assert!(!stderr(&out).contains("panicked"));
+    assert_eq!(t.read("f"), "one\ntwo\nthree\n");
+}
+
+#[test]
+fn five_files() {
+    let t = Scratch::new("five_files");
+    t.write("old/m1", "one\ntwo\n");
+    t.write("old/sub/m2", "three\nfour\n");
+    t.write("new/m1", "one\nTWO\n");
+    t.write("new/sub/m2", "THREE\nfour\n");
+    t.write("new/c1", "c1\n");
+    t.write("new/sub/c2", "c2\n");
+    t.write("new/c3", "c3\n");
+
+    let diff = t.diff(&["-rN", "old", "new"]);
+    assert_eq!(diff.status.code(), Some(1));
+    let patch = diff.stdout;
+
+    // Patch a copy of old into new.
+    t.write("work/m1", "one\ntwo\n");
+    t.write("work/sub/m2", "three\nfour\n");
+    let out = t.patch(&["-p1", "-d", "work"], &patch);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    assert_eq!(stdout(&out), "\
+creating c1
+creating c3
+patching m1
+creating sub/c2
+patching sub/m2
+");
+    for v in ["m1", "sub/m2", "c1", "sub/c2", "c3"] {
+        assert_eq!(t.read(&format!("work/{}", v)), t.read(&format!("new/{}", v)), "{}", v);
+    }
+}
+
+#[test]
+fn no_newline_round_trip() {
+    let t = Scratch::new("no_newline_round_trip");
+
+    // Losing the newline at the end, gaining it, and changing a last line
+    // that has none.
+    for (old, new) in [("a\nb\n", "a\nb"), ("a\nb", "a\nb\n"), ("a\nb", "a\nc")] {
+        t.write("old", old);
+        t.write("new", new);
+        let diff = t.diff(&["old", "new"]);
+        assert!(stdout(&diff).contains("\\ No newline at end of file\n"));
+
+        t.write("work", old);
+        let out = t.patch(&["work"], &diff.stdout);
+        assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+        assert_eq!(t.read("work"), new);
+    }
+}
+
+#[test]
+fn create_nested() {
+    let t = Scratch::new("create_nested");
+    let patch = "--- /dev/null\n+++ b/src/new/deep/file.rs\n@@ -0,0 +1,2 @@\n+fn main() {\n+}\n";
+
+    let out = t.patch(&["-p1"], patch);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    assert_eq!(stdout(&out), "creating src/new/deep/file.rs\n");
+    assert_eq!(t.read("src/new/deep/file.rs"), "fn main() {\n}\n");
+
+    // -p3 leaves just the last directory to make.
+    t.mkdir("empty");
+    let out = t.patch(&["-p3", "-d", "empty"], patch);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    assert_eq!(t.read("empty/deep/file.rs"), "fn main() {\n}\n");
+}
+
+#[test]
+fn quoted_names() {
+    let t = Scratch::new("quoted_names");
+    t.write("we ird\tname", "one\n");
+    let patch = "\
+diff --git \"a/we ird\\tname\" \"b/we ird\\tname\"
+--- \"a/we ird\\tname\"
++++ \"b/we ird\\tname\"
+@@ -1 +1 @@
+-one
++ONE
+";
+
+    let out = t.patch(&["-p1"], patch);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    assert_eq!(t.read("we ird\tname"), "ONE\n");
+}
+
+#[test]
+fn create_and_delete() {
+    let t = Scratch::new("create_and_delete");
+    let epoch = "1970-01-01 00:00:00.000000000 +0000";
+    let now = "2023-01-01 12:00:00.000000000 +0000";
+
+    for (old, new) in [("/dev/null".to_string(), "f".to_string()), (format!("f\t{}", epoch), format!("f\t{}", now))] {
+        let out = t.patch(&[], format!("--- {}\n+++ {}\n@@ -0,0 +1 @@\n+x\n", old, new));
+        assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+        assert_eq!(stdout(&out), "creating f\n");
+        assert_eq!(t.read("f"), "x\n");
+
+        let out = t.patch(&[], format!("--- {}\n+++ {}\n@@ -1 +0,0 @@\n-x\n", new, old));
+        assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+        assert_eq!(stdout(&out), "removing f\n");
+        assert!(!t.exists("f"));
+    }
+}
+
+#[test]
+fn named_file_is_deleted() {
+    let t = Scratch::new("named_file_is_deleted");
+    t.write("keep", "a\n");
+    t.write("tgt", "a\n");
+
+    let out = t.patch(&["tgt"], "--- keep\n+++ /dev/null\n@@ -1 +0,0 @@\n-a\n");
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    assert_eq!(stdout(&out), "removing tgt\n");
+    assert!(!t.exists("tgt"));
+    assert_eq!(t.read("keep"), "a\n");
+
+    // A context diff to an empty file is a deletion too.
+    t.write("tgt", "a\n");
+    let out = t.patch(&["tgt"], "*** keep\n--- keep\n***************\n*** 1 ****\n- a\n--- 0 ----\n");
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    assert_eq!(stdout(&out), "removing tgt\n");
+    assert!(!t.exists("tgt"));
+    assert_eq!(t.read("keep"), "a\n");
+}
+
+#[test]
+fn posix_keeps_empty_files() {
+    let t = Scratch::new("posix_keeps_empty_files");
+    t.write("f", "x\n");
+
+    // An empty side only means creating or removing with /dev/null.
+    let out = t.patch(&["--posix"], "--- f\n+++ f\n@@ -1 +0,0 @@\n-x\n");
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    assert_eq!(stdout(&out), "patching f\n");
+    assert_eq!(t.read("f"), "");
+
+    let out = t.patch(&[], "--- f\n+++ f\n@@ -0,0 +1 @@\n+x\n");
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    let out = t.patch(&[], "--- f\n+++ f\n@@ -1 +0,0 @@\n-x\n");
+    assert_eq!(stdout(&out), "removing f\n");
+    assert!(!t.exists("f"));
+}
+
+#[test]
+fn fuzz_factor() {
+    let t = Scratch::new("fuzz_factor");
+    let orig = "alpha\nbravo\ncharlie\ndelta\necho\n";
+    let patch = "--- f\n+++ f\n@@ -1,5 +1,5 @@\n ALPHA\n bravo\n-charlie\n+CHARLIE\n delta\n ECHO\n";
+
+    // The first and last lines of context don't match, which one line of
+    // fuzz lets go.
+    t.write("f", orig);
+    let out = t.patch(&["-F1", "f"], patch);
+    assert_eq!(out.status.code(), Some(0), "{}", stderr(&out));
+    assert_eq!(t.read("f"), "alpha\nbravo\nCHARLIE\ndelta\necho\n");
+    assert!(!t.exists("f.rej"));
+
+    t.write("f", orig);
+    let out = t.patch(&["-F0", "f"], patch);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(stderr(&out).contains("Hunk 1 FAILED 1/1."), "{}", stderr(&out));
+    assert_eq!(t.read("f"), orig);
+    assert_eq!(t.read("f.rej"), patch);
+}